@@ -10,7 +10,7 @@ then
     exit 1
 fi
 
-EXAMPLE="simple"
+EXAMPLE="${1:-simple}"
 xargo build --target "$TARGET" --example "$EXAMPLE"
 
 unset CC
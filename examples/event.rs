@@ -0,0 +1,41 @@
+#![cfg(target_os = "redox")]
+
+extern crate syscall;
+
+use syscall::data::{Event, TimeSpec};
+use syscall::flag::{CLOCK_MONOTONIC, EVENT_READ, O_RDWR};
+
+fn main() {
+    let time_fd = syscall::open(format!("time:{}", CLOCK_MONOTONIC), O_RDWR).unwrap();
+    let event_fd = syscall::open("event:", O_RDWR).unwrap();
+
+    // Watch the timer before it is armed
+    syscall::write(event_fd, &Event {
+        id: time_fd,
+        flags: EVENT_READ,
+        data: 42,
+    }).unwrap();
+
+    // Arm the timer 10 milliseconds from now
+    let mut time = TimeSpec::default();
+    syscall::read(time_fd, &mut time).unwrap();
+    time.tv_nsec += 10_000_000;
+    if time.tv_nsec >= 1_000_000_000 {
+        time.tv_sec += 1;
+        time.tv_nsec -= 1_000_000_000;
+    }
+    syscall::write(time_fd, &time).unwrap();
+
+    // Reading blocks until the timer expires
+    let mut event = Event::default();
+    let count = syscall::read(event_fd, &mut event).unwrap();
+
+    syscall::close(event_fd).unwrap();
+    syscall::close(time_fd).unwrap();
+
+    if count == event.len() && event.id == time_fd && event.flags == EVENT_READ && event.data == 42 {
+        syscall::exit(0);
+    } else {
+        syscall::exit(1);
+    }
+}
@@ -0,0 +1,31 @@
+#![cfg(target_os = "redox")]
+
+extern crate syscall;
+
+use syscall::flag::{O_CREAT, O_RDONLY, O_WRONLY};
+
+fn main() {
+    let _ = syscall::unlink("link_old");
+    let _ = syscall::unlink("link_new");
+
+    let fd = syscall::open("link_old", O_WRONLY | O_CREAT | 0o644).unwrap();
+    syscall::write(fd, b"link").unwrap();
+    syscall::close(fd).unwrap();
+
+    // Both paths are NUL-terminated
+    unsafe { syscall::link(b"link_old\0".as_ptr(), b"link_new\0".as_ptr()).unwrap() };
+
+    let fd = syscall::open("link_new", O_RDONLY).unwrap();
+    let mut buf = [0; 4];
+    let count = syscall::read(fd, &mut buf).unwrap();
+    syscall::close(fd).unwrap();
+
+    syscall::unlink("link_old").unwrap();
+    syscall::unlink("link_new").unwrap();
+
+    if &buf[..count] == b"link" {
+        syscall::exit(0);
+    } else {
+        syscall::exit(1);
+    }
+}
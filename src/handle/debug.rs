@@ -68,6 +68,11 @@ pub unsafe fn format_call(p: &mut Process, a: usize, b: usize, c: usize, d: usiz
             p.read_type(b as *const u8, c).map(ByteString),
             d
         ),
+        SYS_LINK => format!(
+            "link({:?}, {:?})",
+            p.pread_cstr(b).map(ByteString),
+            p.pread_cstr(c).map(ByteString)
+        ),
         SYS_RMDIR => format!(
             "rmdir({:?})",
             p.read_type(b as *const u8, c).map(ByteString)
@@ -146,6 +151,11 @@ pub unsafe fn format_call(p: &mut Process, a: usize, b: usize, c: usize, d: usiz
             c,
             d
         ),
+        SYS_FRENAME => format!(
+            "frename({}, {:?})",
+            b,
+            p.read_type(c as *const u8, d).map(ByteString)
+        ),
         SYS_FSTAT => format!(
             "fstat({}, {:?})",
             b,
@@ -262,6 +272,12 @@ pub unsafe fn format_call(p: &mut Process, a: usize, b: usize, c: usize, d: usiz
             "mkns({:?})",
             p.read_type(b as *const [usize; 2], c)
         ),
+        SYS_MPROTECT => format!(
+            "mprotect({:#x}, {}, {:#x})",
+            b,
+            c,
+            d
+        ),
         SYS_NANOSLEEP => format!(
             "nanosleep({:?}, ({}, {}))",
             p.read_type(b as *const TimeSpec, 1),
@@ -0,0 +1,17 @@
+//! Linux definitions that are missing from the libc crate
+
+use libc::{c_int, c_uint};
+
+pub const FUTEX_WAIT: c_int = 0;
+pub const FUTEX_WAKE: c_int = 1;
+pub const FUTEX_REQUEUE: c_int = 3;
+
+pub const ERESTARTSYS: c_int = 512;
+pub const ERESTARTNOINTR: c_int = 513;
+pub const ERESTARTNOHAND: c_int = 514;
+pub const ERESTART_RESTARTBLOCK: c_int = 516;
+
+pub const PTRACE_GETSIGMASK: c_uint = 0x420a;
+pub const PTRACE_SETSIGMASK: c_uint = 0x420b;
+
+pub const PTRACE_EVENT_STOP: c_int = 128;
@@ -1,12 +1,91 @@
 use libc;
 use sc::nr;
-use std::{mem, result, str};
+use std::{cmp, fs, mem, result};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use syscall::*;
 
 mod debug;
+mod errno;
+pub mod linux;
+mod signo;
 
-use self::process::Process;
+pub use self::process::Process;
+use self::process::{File, SignalFrame, Stack, Syscall};
+pub use self::scheme::{Access, FileScheme, Scheme, Schemes};
+use self::signo::{convert_lsignal, convert_signal};
 mod process;
+mod scheme;
+
+fn convert_clock(clock: u64) -> Option<libc::clockid_t> {
+    match clock as usize {
+        CLOCK_REALTIME => Some(libc::CLOCK_REALTIME),
+        CLOCK_MONOTONIC => Some(libc::CLOCK_MONOTONIC),
+        _ => None,
+    }
+}
+
+fn convert_clone(flags: u64) -> u64 {
+    let rflags = flags as usize;
+    let mut lflags = 0;
+
+    macro_rules! convert {
+        ($name:ident) => (if rflags & syscall::flag::$name > 0 {
+            lflags |= libc::$name;
+        });
+    }
+
+    convert!(CLONE_VM);
+    convert!(CLONE_FS);
+    convert!(CLONE_FILES);
+    convert!(CLONE_SIGHAND);
+    convert!(CLONE_VFORK);
+    convert!(CLONE_THREAD);
+
+    // Redox can wait for any child, but Linux only reports children that
+    // send SIGCHLD when they exit
+    if rflags & CLONE_THREAD == 0 {
+        lflags |= libc::SIGCHLD;
+    }
+
+    lflags as u64
+}
+
+fn convert_fcntl(cmd: u64) -> Option<libc::c_int> {
+    match cmd as usize {
+        F_DUPFD => Some(libc::F_DUPFD),
+        F_GETFD => Some(libc::F_GETFD),
+        F_SETFD => Some(libc::F_SETFD),
+        F_GETFL => Some(libc::F_GETFL),
+        F_SETFL => Some(libc::F_SETFL),
+        _ => None,
+    }
+}
+
+fn convert_futex(op: u64) -> Option<libc::c_int> {
+    match op as usize {
+        FUTEX_WAIT => Some(linux::FUTEX_WAIT),
+        FUTEX_WAKE => Some(linux::FUTEX_WAKE),
+        FUTEX_REQUEUE => Some(linux::FUTEX_REQUEUE),
+        _ => None,
+    }
+}
+
+fn convert_map(fd: u64, flags: usize) -> (u64, u64) {
+    let mut lflags = if flags & MAP_PRIVATE > 0 {
+        libc::MAP_PRIVATE
+    } else {
+        libc::MAP_SHARED
+    };
+
+    // Redox uses !0 as the file descriptor for anonymous memory
+    if fd == !0 {
+        lflags |= libc::MAP_ANONYMOUS;
+    }
+
+    (convert_prot(flags), lflags as u64)
+}
 
 fn convert_open(flags: u64) -> (u64, u64) {
     let rflags = flags as usize;
@@ -39,29 +118,180 @@ fn convert_open(flags: u64) -> (u64, u64) {
     (lflags as u64, flags & 0xFFFF)
 }
 
-fn convert_path(rpath: &[u8]) -> Vec<u8> {
-    let mut lpath = if rpath.contains(&b':') {
-        let mut parts = rpath.splitn(2, |b| b == &b':');
-        let scheme = parts.next().unwrap();
-        let path = parts.next().unwrap();
-        match scheme {
-            b"null" => b"/dev/null".to_vec(),
-            b"rand" => b"/dev/urandom".to_vec(),
-            b"zero" => b"/dev/zero".to_vec(),
-            _ => {
-                println!(
-                    "TODO: {}:{}",
-                    unsafe { str::from_utf8_unchecked(&scheme) },
-                    unsafe { str::from_utf8_unchecked(&path) }
-                );
-                rpath.to_vec()
+fn convert_sigset(set: &[u64; 2]) -> u64 {
+    let mut lset = 0;
+    for sig in 1..=64 {
+        if set[0] & (1 << (sig - 1)) > 0 {
+            if let Some(lsig) = convert_signal(sig) {
+                lset |= 1 << (lsig - 1);
             }
         }
+    }
+    lset
+}
+
+fn convert_wait(flags: u64) -> u64 {
+    let rflags = flags as usize;
+    let mut lflags = 0;
+
+    macro_rules! convert {
+        ($name:ident) => (if rflags & syscall::flag::$name > 0 {
+            lflags |= libc::$name;
+        });
+    }
+
+    convert!(WNOHANG);
+    convert!(WUNTRACED);
+    convert!(WCONTINUED);
+
+    lflags as u64
+}
+
+fn convert_lopen(flags: u64) -> u64 {
+    let lflags = flags as libc::c_int;
+    let mut rflags = match lflags & libc::O_ACCMODE {
+        libc::O_RDONLY => O_RDONLY,
+        libc::O_WRONLY => O_WRONLY,
+        libc::O_RDWR => O_RDWR,
+        _ => 0,
+    };
+
+    macro_rules! convert {
+        ($name:ident) => (if lflags & libc::$name > 0 {
+            rflags |= syscall::flag::$name;
+        });
+    }
+
+    convert!(O_NONBLOCK);
+    convert!(O_CREAT);
+    convert!(O_EXCL);
+    convert!(O_TRUNC);
+    convert!(O_APPEND);
+    convert!(O_DIRECTORY);
+    convert!(O_NOFOLLOW);
+    convert!(O_CLOEXEC);
+    if lflags & libc::O_PATH > 0 {
+        rflags |= syscall::flag::O_STAT;
+    }
+
+    rflags as u64
+}
+
+/// Resolve a Redox path against the working directory, like the Redox kernel
+/// does. Paths with a scheme are absolute, paths starting with `/` are
+/// relative to the scheme of the working directory, and all other paths are
+/// relative to the working directory itself.
+fn canonicalize(cwd: &[u8], rpath: &[u8]) -> Vec<u8> {
+    let canon = if rpath.contains(&b':') {
+        rpath.to_vec()
+    } else if rpath.starts_with(b"/") {
+        let scheme_len = cwd.iter().position(|b| b == &b':').map_or(0, |i| i + 1);
+        let mut canon = cwd[..scheme_len].to_vec();
+        canon.extend_from_slice(rpath);
+        canon
+    } else {
+        let mut canon = cwd.to_vec();
+        canon.push(b'/');
+        canon.extend_from_slice(rpath);
+        canon
+    };
+
+    // The first part contains the scheme, and cannot be removed by ..
+    let mut parts = Vec::new();
+    for part in canon.split(|b| b == &b'/') {
+        match part {
+            b"" | b"." => (),
+            b".." => if parts.len() > 1 {
+                parts.pop();
+            },
+            _ => parts.push(part),
+        }
+    }
+    parts.join(&b'/')
+}
+
+/// Split a Redox path into its scheme and the path inside the scheme
+fn split_path(rpath: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut parts = rpath.splitn(2, |b| b == &b':');
+    let scheme = parts.next()?;
+    let path = parts.next()?;
+    Some((scheme, path))
+}
+
+/// Convert a Redox path into a Linux C string, or `None` if the scheme is
+/// emulated and has no Linux paths
+fn convert_path_opt(schemes: &Schemes, rpath: &[u8], access: Access, follow: bool) -> Option<Result<Vec<u8>>> {
+    let mut lpath = if let Some((scheme, path)) = split_path(rpath) {
+        match schemes.get(scheme) {
+            Some(scheme) => match scheme.path(path, access, follow)? {
+                Ok(lpath) => lpath,
+                Err(err) => return Some(Err(err)),
+            },
+            None => return Some(Err(Error::new(ENODEV))),
+        }
     } else {
         rpath.to_vec()
     };
     lpath.push(0);
-    lpath
+    Some(Ok(lpath))
+}
+
+fn convert_path(schemes: &Schemes, rpath: &[u8], access: Access, follow: bool) -> Result<Vec<u8>> {
+    // Emulated schemes do not support system calls on paths, other than open
+    convert_path_opt(schemes, rpath, access, follow).unwrap_or(Err(Error::new(ENOENT)))
+}
+
+/// Check if a Linux C string is the path of a directory
+fn is_dir(lpath: &[u8]) -> bool {
+    let lpath = &lpath[..lpath.len() - 1];
+    fs::metadata(Path::new(OsStr::from_bytes(lpath))).map(|metadata| metadata.is_dir()).unwrap_or(false)
+}
+
+/// Remove a path in a scheme that removes files itself. Returns `None` if
+/// Linux should remove the file
+fn remove_path(schemes: &Schemes, rpath: &[u8], dir: bool) -> Option<Result<usize>> {
+    let (scheme, path) = split_path(rpath)?;
+    schemes.get(scheme)?.remove(path, dir)
+}
+
+/// Rename a path in a scheme that renames files itself. Returns `None` if
+/// Linux should rename the file
+fn rename_path(schemes: &Schemes, old_rpath: &[u8], rpath: &[u8]) -> Option<Result<usize>> {
+    let (old_scheme, old_path) = split_path(old_rpath)?;
+    let (scheme, path) = split_path(rpath)?;
+    if old_scheme != scheme {
+        return Some(Err(Error::new(EXDEV)));
+    }
+    schemes.get(scheme)?.rename(old_path, path)
+}
+
+/// Find the Linux path that changes to the file of a file descriptor go to,
+/// if the file is in a read-only layer. It is copied to the writable layer,
+/// and its C string is returned. Returns `None` if the file descriptor can
+/// be changed directly
+fn upper_path(p: &Process, fd: u64) -> Option<Result<Vec<u8>>> {
+    let link = fs::read_link(format!("/proc/{}/fd/{}", p.pid, fd as libc::c_int)).ok()?;
+    let mut lpath = link.as_os_str().as_bytes().to_vec();
+    let schemes = p.schemes.borrow();
+    let rpath = convert_lpath(&schemes, &lpath);
+    lpath.push(0);
+
+    // Files that were removed or replaced are not copied
+    match convert_path_opt(&schemes, &rpath, Access::Read, false)? {
+        Ok(ref read_lpath) if read_lpath == &lpath => (),
+        _ => return None,
+    }
+    match convert_path_opt(&schemes, &rpath, Access::Write, false)? {
+        Ok(ref write_lpath) if write_lpath == &lpath => None,
+        res => Some(res),
+    }
+}
+
+fn convert_lpath(schemes: &Schemes, lpath: &[u8]) -> Vec<u8> {
+    // Pipes, sockets and other anonymous files already look like Redox
+    // paths, for example pipe:[1234]. Files outside of the schemes, such as
+    // an inherited terminal, keep their Linux path
+    schemes.rpath(lpath).unwrap_or_else(|| lpath.to_vec())
 }
 
 fn convert_pipe(lpipe: &[libc::c_int]) -> Vec<usize> {
@@ -94,25 +324,558 @@ fn convert_stat(lstat: &[u8]) -> Stat {
     }
 }
 
+fn convert_statvfs(lstatfs: &[u8]) -> StatVfs {
+    let statfs = unsafe { &*(lstatfs.as_ptr() as *const libc::statfs) };
+
+    StatVfs {
+        f_bsize: statfs.f_bsize as _,
+        f_blocks: statfs.f_blocks as _,
+        f_bfree: statfs.f_bfree as _,
+        f_bavail: statfs.f_bavail as _,
+    }
+}
+
+fn convert_timespec(ltimespec: &[u8]) -> TimeSpec {
+    let timespec = unsafe { &*(ltimespec.as_ptr() as *const libc::timespec) };
+
+    TimeSpec {
+        tv_sec: timespec.tv_sec as _,
+        tv_nsec: timespec.tv_nsec as _,
+    }
+}
+
+fn convert_lsigset(lset: u64) -> [u64; 2] {
+    let mut set = [0; 2];
+    for lsig in 1..=64 {
+        if lset & (1 << (lsig - 1)) > 0 {
+            if let Some(sig) = convert_lsignal(lsig) {
+                set[(sig - 1) / 64] |= 1 << ((sig - 1) % 64);
+            }
+        }
+    }
+    set
+}
+
+unsafe fn convert_wstatus(lstatus: libc::c_int) -> usize {
+    if libc::WIFEXITED(lstatus) {
+        (libc::WEXITSTATUS(lstatus) as usize & 0xff) << 8
+    } else if libc::WIFSIGNALED(lstatus) {
+        let sig = convert_lsignal(libc::WTERMSIG(lstatus)).unwrap_or(SIGKILL);
+        let mut status = sig & 0x7f;
+        if libc::WCOREDUMP(lstatus) {
+            status |= 0x80;
+        }
+        status
+    } else if libc::WIFSTOPPED(lstatus) {
+        let sig = convert_lsignal(libc::WSTOPSIG(lstatus)).unwrap_or(SIGSTOP);
+        (sig & 0xff) << 8 | 0x7f
+    } else if libc::WIFCONTINUED(lstatus) {
+        0xffff
+    } else {
+        lstatus as usize
+    }
+}
+
+fn convert_prot(flags: usize) -> u64 {
+    let mut lprot = libc::PROT_NONE;
+
+    macro_rules! convert {
+        ($name:ident) => (if flags & syscall::flag::$name > 0 {
+            lprot |= libc::$name;
+        });
+    }
+
+    convert!(PROT_READ);
+    convert!(PROT_WRITE);
+    convert!(PROT_EXEC);
+
+    lprot as u64
+}
+
+/// Redox uses the same special nanosecond values as Linux, but the syscall
+/// crate does not define them
+const UTIME_NOW: i32 = (1 << 30) - 1;
+const UTIME_OMIT: i32 = (1 << 30) - 2;
+
+fn convert_rtime(rtime: &TimeSpec) -> libc::timespec {
+    match rtime.tv_nsec {
+        UTIME_NOW => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+        UTIME_OMIT => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        _ => convert_rtimespec(rtime),
+    }
+}
+
+fn convert_rtimespec(rtimespec: &TimeSpec) -> libc::timespec {
+    libc::timespec {
+        tv_sec: rtimespec.tv_sec as _,
+        tv_nsec: rtimespec.tv_nsec as _,
+    }
+}
+
 const PAGE_SIZE: usize = 4096;
 
-pub unsafe fn handle(pid: libc::pid_t) -> result::Result<(), i32> {
+/// Write C strings to the scratch memory of the tracee, returning their addresses
+unsafe fn scratch_paths(p: &mut Process, lpaths: &[&[u8]]) -> result::Result<Result<Vec<u64>>, i32> {
+    let size = lpaths.iter().map(|lpath| lpath.len()).sum::<usize>();
+    let mut addr = match p.scratch(size)? {
+        Ok(addr) => addr,
+        Err(err) => return Ok(Err(err)),
+    };
+
+    let mut addrs = Vec::with_capacity(lpaths.len());
+    for lpath in lpaths.iter() {
+        p.pwrite(addr, lpath).unwrap();
+        addrs.push(addr as u64);
+        addr += lpath.len();
+    }
+
+    Ok(Ok(addrs))
+}
+
+/// Convert the raw result of an injected Linux system call into a Redox result
+fn convert_lresult(lresult: u64) -> Result<usize> {
+    let lerrno = -(lresult as i64);
+    if lerrno >= 512 && lerrno < 4096 {
+        // Injected system calls are not restarted
+        Err(Error::new(EINTR))
+    } else if lerrno >= 1 && lerrno < 512 {
+        let errno = errno::convert_errno(lerrno as i32).unwrap_or(lerrno as i32);
+        Err(Error::new(errno))
+    } else {
+        Ok(lresult as usize)
+    }
+}
+
+/// Find the Linux system call that performs a Redox dup of `fd` with `buf`.
+/// An empty buffer clones the file descriptor, otherwise the scheme of the
+/// file decides which new resource is created
+fn convert_dup(pid: libc::pid_t, fd: u64, buf: &[u8]) -> Result<usize> {
+    if buf.is_empty() {
+        return Ok(nr::DUP);
+    }
+
+    let link = format!("/proc/{}/fd/{}", pid, fd as libc::c_int);
+    let lpath = fs::read_link(link).map_err(|_| Error::new(EBADF))?;
+
+    // Network schemes accept a new connection on dup with "listen"
+    if lpath.as_os_str().as_bytes().starts_with(b"socket:") && buf == b"listen" {
+        Ok(nr::ACCEPT)
+    } else {
+        Err(Error::new(EINVAL))
+    }
+}
+
+/// Open a path in an emulated scheme, and reserve its file descriptor number
+/// with a Linux placeholder
+unsafe fn open_file(p: &mut Process, rpath: &[u8], flags: u64) -> result::Result<Result<usize>, i32> {
+    let (scheme, path) = match split_path(rpath) {
+        Some(parts) => parts,
+        None => return Ok(Err(Error::new(ENOENT))),
+    };
+
+    // The path of the placeholder is written first, so that the resource
+    // does not have to be closed if that fails
+    let addrs = match scratch_paths(p, &[b"/dev/null\0"])? {
+        Ok(addrs) => addrs,
+        Err(err) => return Ok(Err(err)),
+    };
+
+    let schemes = p.schemes.clone();
+    let id = match schemes.borrow_mut().call(scheme, |scheme| scheme.open(path, flags as usize)) {
+        Ok(id) => id,
+        // Schemes that do not exist return ENODEV
+        Err(err) => return Ok(Err(err)),
+    };
+
+    // The placeholder gets O_CLOEXEC, so Linux closes it on exec if needed
+    let oflag = libc::O_RDONLY | if flags as usize & O_CLOEXEC > 0 { libc::O_CLOEXEC } else { 0 };
+    let result = p.inject(nr::OPEN, [addrs[0], oflag as u64, 0, 0, 0, 0])?;
+    Ok(match convert_lresult(result) {
+        Ok(fd) => {
+            p.files.borrow_mut().insert(fd, File {
+                scheme: scheme.to_vec(),
+                id,
+            });
+            Ok(fd)
+        },
+        Err(err) => {
+            let _ = schemes.borrow_mut().call(scheme, |scheme| scheme.close(id));
+            Err(err)
+        }
+    })
+}
+
+/// Duplicate an emulated resource, and its placeholder with the Linux system
+/// call `lnr`
+unsafe fn dup_file(p: &mut Process, file: &File, buf: &[u8], lnr: usize, largs: [u64; 6]) -> result::Result<Result<usize>, i32> {
+    let schemes = p.schemes.clone();
+    let id = match schemes.borrow_mut().call(&file.scheme, |scheme| scheme.dup(file.id, buf)) {
+        Ok(id) => id,
+        Err(err) => return Ok(Err(err)),
+    };
+
+    let result = p.inject(lnr, largs)?;
+    Ok(match convert_lresult(result) {
+        Ok(fd) => {
+            // Linux closed the placeholder if the new file descriptor was used
+            let _ = close_file(p, fd);
+            p.files.borrow_mut().insert(fd, File {
+                scheme: file.scheme.clone(),
+                id,
+            });
+            Ok(fd)
+        },
+        Err(err) => {
+            let _ = schemes.borrow_mut().call(&file.scheme, |scheme| scheme.close(id));
+            Err(err)
+        }
+    })
+}
+
+/// Close the emulated resource of a file descriptor, if it has one. Linux
+/// closes the placeholder
+fn close_file(p: &mut Process, fd: usize) -> Result<usize> {
+    let file_opt = p.files.borrow_mut().remove(&fd);
+    match file_opt {
+        Some(file) => p.schemes.borrow_mut().call(&file.scheme, |scheme| scheme.close(file.id)),
+        None => Ok(0),
+    }
+}
+
+/// Handle a system call on a file descriptor with an emulated resource
+unsafe fn handle_file(p: &mut Process, file: &File, a: u64, b: u64, c: u64, d: u64, e: u64) -> result::Result<Result<usize>, i32> {
+    let schemes = p.schemes.clone();
+    Ok(match a as usize {
+        SYS_CLOSE => {
+            p.inject(nr::CLOSE, [b, 0, 0, 0, 0, 0])?;
+            close_file(p, b as usize)
+        },
+        SYS_DUP => {
+            let buf = p.pread(c as usize, d as usize).unwrap();
+            dup_file(p, file, &buf, nr::DUP, [b, 0, 0, 0, 0, 0])?
+        },
+        SYS_DUP2 => {
+            let buf = p.pread(d as usize, e as usize).unwrap();
+            dup_file(p, file, &buf, nr::DUP2, [b, c, 0, 0, 0, 0])?
+        },
+        SYS_FCHMOD => {
+            schemes.borrow_mut().call(&file.scheme, |scheme| scheme.fchmod(file.id, c as u16))
+        },
+        SYS_FCHOWN => {
+            schemes.borrow_mut().call(&file.scheme, |scheme| scheme.fchown(file.id, c as u32, d as u32))
+        },
+        SYS_FCNTL => {
+            dup_file(p, file, b"", nr::FCNTL, [b, libc::F_DUPFD as u64, d, 0, 0, 0])?
+        },
+        SYS_FEVENT => {
+            schemes.borrow_mut().call(&file.scheme, |scheme| scheme.fevent(file.id, c as usize))
+        },
+        SYS_FMAP => if d as usize >= mem::size_of::<Map>() {
+            let map = p.read_type(c as *const Map, 1).unwrap()[0];
+            schemes.borrow_mut().call(&file.scheme, |scheme| scheme.fmap(file.id, &map))
+        } else {
+            Err(Error::new(EINVAL))
+        },
+        SYS_FPATH => {
+            let mut buf = vec![0; d as usize];
+            let res = schemes.borrow_mut().call(&file.scheme, |scheme| scheme.fpath(file.id, &mut buf));
+            res.and_then(|count| p.pwrite(c as usize, &buf[..count]).map(|()| count))
+        },
+        SYS_FRENAME => {
+            // Files can only be renamed inside of their scheme
+            let rpath = p.pread(c as usize, d as usize).unwrap();
+            let rpath = canonicalize(&p.cwd.borrow(), &rpath);
+            match split_path(&rpath) {
+                Some((scheme_name, path)) if scheme_name == &file.scheme[..] => {
+                    schemes.borrow_mut().call(&file.scheme, |scheme| scheme.frename(file.id, path))
+                },
+                _ => Err(Error::new(EXDEV)),
+            }
+        },
+        SYS_FSTAT => {
+            let mut stat = Stat::default();
+            let res = schemes.borrow_mut().call(&file.scheme, |scheme| scheme.fstat(file.id, &mut stat));
+            res.and_then(|count| p.write_type(c as *mut Stat, &[stat]).map(|()| count))
+        },
+        SYS_FSTATVFS => {
+            let mut stat = StatVfs::default();
+            let res = schemes.borrow_mut().call(&file.scheme, |scheme| scheme.fstatvfs(file.id, &mut stat));
+            res.and_then(|count| p.write_type(c as *mut StatVfs, &[stat]).map(|()| count))
+        },
+        SYS_FSYNC => {
+            schemes.borrow_mut().call(&file.scheme, |scheme| scheme.fsync(file.id))
+        },
+        SYS_FTRUNCATE => {
+            schemes.borrow_mut().call(&file.scheme, |scheme| scheme.ftruncate(file.id, c as usize))
+        },
+        SYS_FUTIMENS => {
+            let count = d as usize / mem::size_of::<TimeSpec>();
+            let times = p.read_type(c as *const TimeSpec, count).unwrap();
+            schemes.borrow_mut().call(&file.scheme, |scheme| scheme.futimens(file.id, &times))
+        },
+        SYS_LSEEK => {
+            schemes.borrow_mut().call(&file.scheme, |scheme| scheme.seek(file.id, c as usize, d as usize))
+        },
+        SYS_READ => {
+            let mut buf = vec![0; d as usize];
+            let res = schemes.borrow_mut().call(&file.scheme, |scheme| scheme.read(file.id, &mut buf));
+            res.and_then(|count| p.pwrite(c as usize, &buf[..count]).map(|()| count))
+        },
+        SYS_WRITE if file.scheme == b"event" => {
+            write_events(p, file, c, d)
+        },
+        SYS_WRITE => {
+            let buf = p.pread(c as usize, d as usize).unwrap();
+            schemes.borrow_mut().call(&file.scheme, |scheme| scheme.write(file.id, &buf))
+        },
+        // Emulated resources cannot be executed
+        _ => Err(Error::new(EBADF)),
+    })
+}
+
+/// Register the events written to an event queue with the schemes of their
+/// file descriptors
+unsafe fn write_events(p: &mut Process, file: &File, c: u64, d: u64) -> Result<usize> {
+    let count = d as usize / mem::size_of::<Event>();
+    let events = p.read_type(c as *const Event, count).unwrap();
+    for event in events.iter() {
+        // Only emulated resources have events
+        let target = p.files.borrow().get(&event.id).cloned().ok_or(Error::new(EBADF))?;
+        p.schemes.borrow_mut().fevent(file.id, event.id, &target.scheme, target.id, event.flags, event.data)?;
+    }
+    Ok(count * mem::size_of::<Event>())
+}
+
+/// Read the events of an event queue. If none are ready, the tracee sleeps
+/// until the next one may be, and `None` is returned. The read is tried
+/// again on exit
+unsafe fn read_events(p: &mut Process, file: &File, c: u64, d: u64) -> result::Result<Option<Result<usize>>, i32> {
+    let count = d as usize / mem::size_of::<Event>();
+    let events_res = p.schemes.borrow_mut().events(file.id, count);
+    match events_res {
+        Ok(ref events) if events.is_empty() && count > 0 => (),
+        Ok(events) => {
+            let res = p.write_type(c as *mut Event, &events).map(|()| events.len() * mem::size_of::<Event>());
+            return Ok(Some(res));
+        },
+        Err(err) => return Ok(Some(Err(err))),
+    }
+
+    let timeout = match p.schemes.borrow().event_timeout(file.id) {
+        Ok(timeout) => timeout,
+        Err(err) => return Ok(Some(Err(err))),
+    };
+
+    // Get scratch memory for the Linux structure
+    let scratch = match p.scratch(mem::size_of::<libc::timespec>())? {
+        Ok(scratch) => scratch,
+        Err(err) => return Ok(Some(Err(err))),
+    };
+    let ltimespec = libc::timespec {
+        tv_sec: timeout.as_secs() as _,
+        tv_nsec: timeout.subsec_nanos() as _,
+    };
+    p.write_type(scratch as *mut libc::timespec, &[ltimespec]).unwrap();
+
+    // Set up the new arguments. This blocks in the tracee, not in rine
+    p.set_nr(nr::NANOSLEEP);
+    p.set_b(scratch as u64);
+    p.set_c(0);
+    p.set();
+    Ok(None)
+}
+
+/// Find the memory mapping of the tracee that contains an address
+fn find_mapping(pid: libc::pid_t, address: usize) -> Option<(usize, usize)> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid)).ok()?;
+    for line in maps.lines() {
+        let range = line.split(' ').next()?;
+        let mut parts = range.splitn(2, '-');
+        let start = usize::from_str_radix(parts.next()?, 16).ok()?;
+        let end = usize::from_str_radix(parts.next()?, 16).ok()?;
+        if address >= start && address < end {
+            return Some((start, end));
+        }
+    }
+    None
+}
+
+/// Free space given to the stack of threads created by SYS_CLONE
+const CLONE_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Space below the stack pointer that may be used without moving it
+const RED_ZONE_SIZE: usize = 128;
+
+/// Redox gives a child cloned with CLONE_VM a copy of the stack at the same
+/// address, which is not possible when sharing memory on Linux. Instead, map
+/// a new stack and copy the used part of the old one and the red zone to it,
+/// returning the stack and the stack pointer for the child. A frame pointer
+/// into the old stack is moved as well, and the parent gets it back on exit,
+/// but other pointers into the old stack are not updated.
+unsafe fn clone_stack(p: &mut Process) -> result::Result<Result<(Stack, usize)>, i32> {
+    let rsp = p.regs.rsp as usize;
+    let end = match find_mapping(p.pid, rsp) {
+        Some((_start, end)) => end,
+        None => return Ok(Err(Error::new(EFAULT))),
+    };
+
+    let used = end - rsp;
+    let size = (used + RED_ZONE_SIZE + CLONE_STACK_SIZE + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let result = p.inject(nr::MMAP, [
+        0,
+        size as u64,
+        (libc::PROT_READ | libc::PROT_WRITE) as u64,
+        (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK) as u64,
+        !0,
+        0
+    ])?;
+
+    let address = match Error::demux(result as usize) {
+        Ok(address) => address,
+        Err(_) => return Ok(Err(Error::new(ENOMEM))),
+    };
+
+    let stack = p.pread(rsp - RED_ZONE_SIZE, RED_ZONE_SIZE + used).unwrap();
+    let new_rsp = address + size - used;
+    p.pwrite(new_rsp - RED_ZONE_SIZE, &stack).unwrap();
+
+    // The child inherits the frame pointer of the parent
+    let rbp = p.regs.rbp as usize;
+    if rbp >= rsp && rbp < end {
+        p.regs.rbp = (rbp - rsp + new_rsp) as u64;
+    }
+
+    Ok(Ok((Stack { address, size }, new_rsp)))
+}
+
+/// Deliver a Linux signal to a thread in a signal-delivery-stop. Returns the
+/// Linux signal that should be passed on, or zero if rine handled it
+pub unsafe fn signal(p: &mut Process, lsignal: libc::c_int) -> result::Result<libc::c_int, i32> {
+    let sig = match convert_lsignal(lsignal) {
+        Some(sig) => sig,
+        None => return Ok(lsignal),
+    };
+
+    let action_opt = p.sigactions.borrow().get(&sig).cloned();
+    let (action, restorer) = match action_opt {
+        Some(action) => action,
+        // The default action is the same on Linux
+        None => return Ok(lsignal),
+    };
+
+    let handler = match action.sa_handler as usize {
+        SIG_DFL => return Ok(lsignal),
+        SIG_IGN => return Ok(0),
+        handler => handler,
+    };
+
+    // Save the interrupted context for SYS_SIGRETURN
+    p.get();
+    let mut frame = SignalFrame {
+        regs: p.regs,
+        fpregs: p.get_fp(),
+        mask: p.get_mask(),
+    };
+
+    // An interrupted system call returns EINTR, or is restarted when the
+    // handler returns. The Redox system call is still in orig_rax
+    if (p.regs.orig_rax as i64) >= 0 {
+        let restart = match -(p.regs.rax as i64) as libc::c_int {
+            linux::ERESTARTSYS => Some(action.sa_flags & SA_RESTART > 0),
+            linux::ERESTARTNOINTR => Some(true),
+            linux::ERESTARTNOHAND | linux::ERESTART_RESTARTBLOCK => Some(false),
+            _ => None,
+        };
+
+        match restart {
+            Some(true) => {
+                frame.regs.rip -= 2;
+                frame.regs.rax = frame.regs.orig_rax;
+            },
+            Some(false) => {
+                frame.regs.rax = Error::mux(Err(Error::new(EINTR))) as u64;
+            },
+            None => ()
+        }
+    }
+
+    // Like the Redox kernel, call the handler with the signal number and
+    // the restorer as the return address, skipping the red zone
+    let mut sp = (p.regs.rsp as usize - 128) & !15;
+    sp -= mem::size_of::<usize>();
+    p.write_type(sp as *mut usize, &[restorer]).unwrap();
+
+    p.regs.rsp = sp as u64;
+    p.regs.rip = handler as u64;
+    p.regs.rdi = sig as u64;
+    // Clear the direction flag, as required by the ABI
+    p.regs.eflags &= !0x400;
+    // Keep Linux from restarting the interrupted system call itself
+    p.regs.orig_rax = !0;
+    p.set();
+
+    // Block the signal itself unless SA_NODEFER, and the signals of the
+    // action, while the handler runs
+    let mut mask = frame.mask | convert_sigset(&action.sa_mask);
+    if action.sa_flags & SA_NODEFER == 0 {
+        mask |= 1 << (lsignal - 1);
+    }
+    p.set_mask(mask);
+
+    debug!("signal {} => {:#x}", sig, handler);
+    p.sigframes.push(frame);
+
+    if action.sa_flags & SA_RESETHAND > 0 {
+        p.sigactions.borrow_mut().remove(&sig);
+    }
+
+    Ok(0)
+}
+
+/// Unwrap the result of preparing a system call in `translate`, or skip the
+/// system call with the error, for example if scratch memory cannot be
+/// mapped
+macro_rules! try_emulate {
+    ($p:expr, $emulated:expr, $res:expr) => (match $res {
+        Ok(value) => value,
+        Err(err) => {
+            emulate($p, $emulated, Err(err));
+            return Ok(());
+        }
+    })
+}
+
+/// Skip the Linux system call, and return `res` to the tracee instead
+unsafe fn emulate(p: &mut Process, emulated: &mut Option<Result<usize>>, res: Result<usize>) {
+    *emulated = Some(res);
+
+    p.set_nr(!0);
+    p.set();
+}
+
+pub unsafe fn handle(p: &mut Process) -> result::Result<(), i32> {
     // x86_64 syscall convention
     // rax, rdi, rsi, rdx, r10, r8, r9
     // Return value in rax
     // Clobbers rcx, r11
 
-    let mut p = Process::new(pid);
-
-    p.step()?;
     p.get();
 
+    // Each system call stops twice, once on entry and once on exit
+    match p.syscall.take() {
+        Some(call) => handle_exit(p, call),
+        None => handle_entry(p),
+    }
+}
+
+unsafe fn handle_entry(p: &mut Process) -> result::Result<(), i32> {
     let (a, b, c, d, e, f) = p.args();
+    let g = p.regs.r9;
 
     let format_call = true;
     if format_call {
         debug!("{}", debug::format_call(
-            &mut p,
+            p,
             a as usize,
             b as usize,
             c as usize,
@@ -124,270 +887,961 @@ pub unsafe fn handle(pid: libc::pid_t) -> result::Result<(), i32> {
         debug!("{} {:#x}({} {:#x}, {} {:#x}, {} {:#x}, {} {:#x}, {} {:#x})", a, a, b, b, c, c, d, d, e, e, f, f);
     }
 
+    let mut call = Syscall { a, b, c, d, e, f, g, emulated: None, clone_stack: None };
+    let res = translate(p, &mut call);
+    p.syscall = Some(call);
+    res
+}
+
+/// Set up the Linux system call for a Redox system call, or skip it with an
+/// emulated result. Returning early keeps what was set up so far
+unsafe fn translate(p: &mut Process, call: &mut Syscall) -> result::Result<(), i32> {
+    let (a, b, c, d, e, f) = (call.a, call.b, call.c, call.d, call.e, call.f);
+    // Result of a system call that was handled by rine instead of Linux
+    let emulated = &mut call.emulated;
+    // Stack mapped for the child of SYS_CLONE
+    let clone_stack_opt = &mut call.clone_stack;
+
+    // File descriptors with emulated resources are handled by their scheme.
+    // Other fcntl commands apply to the Linux placeholder, and funmap takes
+    // an address
+    let file_opt = match a as usize {
+        SYS_FCNTL if c as usize != F_DUPFD => None,
+        SYS_FUNMAP => None,
+        _ if a as usize & SYS_CLASS == SYS_CLASS_FILE => p.files.borrow().get(&(b as usize)).cloned(),
+        _ => None,
+    };
+
+    if let Some(file) = file_opt {
+        // Event queues wait in the tracee until events may be ready
+        let res_opt = if a as usize == SYS_READ && file.scheme == b"event" {
+            read_events(p, &file, c, d)?
+        } else {
+            Some(handle_file(p, &file, a, b, c, d, e)?)
+        };
+        if let Some(res) = res_opt {
+            emulate(p, emulated, res);
+        }
+
+        return Ok(());
+    }
+
     match a as usize {
         SYS_BRK => {
             p.set_nr(nr::BRK);
             p.set();
-            p.step()?;
         },
         SYS_CHDIR => {
-            //TODO
-            p.set_nr(!0);
-            p.set();
-            p.step()?;
+            // Resolve the new working directory and convert it into a C string
+            let rpath = p.pread(b as usize, c as usize).unwrap();
+            let lpath_res = convert_path(&p.schemes.borrow(), &canonicalize(&p.cwd.borrow(), &rpath), Access::Read, true);
+            match lpath_res {
+                Ok(lpath) => {
+                    let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&lpath])?);
+
+                    // Set up the new arguments, Linux will check that the path
+                    // is a directory and keep its own working directory in sync
+                    p.set_nr(nr::CHDIR);
+                    p.set_b(addrs[0]);
+                    p.set();
+                },
+                Err(err) => emulate(p, emulated, Err(err)),
+            }
         },
-        SYS_CLOCK_GETTIME => {
-            //TODO
-            p.set_nr(!0);
+        SYS_CHMOD => {
+            // Convert the path into a C string
+            let rpath = p.pread(b as usize, c as usize).unwrap();
+            let lpath_res = convert_path(&p.schemes.borrow(), &canonicalize(&p.cwd.borrow(), &rpath), Access::Write, true);
+            match lpath_res {
+                Ok(lpath) => {
+                    let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&lpath])?);
+
+                    // Set up the new arguments
+                    p.set_nr(nr::CHMOD);
+                    p.set_b(addrs[0]);
+                    p.set_c(d & MODE_PERM as u64);
+                    p.set();
+                },
+                Err(err) => emulate(p, emulated, Err(err)),
+            }
+        },
+        SYS_CLOCK_GETTIME => if let Some(clock) = convert_clock(b) {
+            // Get scratch memory for the Linux structure
+            let scratch = try_emulate!(p, emulated, p.scratch(mem::size_of::<libc::timespec>())?);
+
+            // Set up the new arguments
+            p.set_nr(nr::CLOCK_GETTIME);
+            p.set_b(clock as u64);
+            p.set_c(scratch as u64);
             p.set();
-            p.step()?;
+        } else {
+            emulate(p, emulated, Err(Error::new(EINVAL)));
+        },
+        SYS_CLONE => {
+            let stack_res = if b as usize & CLONE_VM > 0 {
+                clone_stack(p)?.map(Some)
+            } else {
+                Ok(None)
+            };
+
+            match stack_res {
+                Ok(stack_opt) => {
+                    let new_rsp = stack_opt.map_or(0, |(_stack, new_rsp)| new_rsp);
+                    *clone_stack_opt = stack_opt.map(|(stack, _new_rsp)| stack);
+
+                    // Set up the new arguments. The child is attached to rine
+                    // by PTRACE_O_TRACECLONE, and gets a return value of 0
+                    p.set_nr(nr::CLONE);
+                    p.set_b(convert_clone(b));
+                    p.set_c(new_rsp as u64);
+                    p.set_d(0);
+                    p.set_e(0);
+                    p.set_f(0);
+                    p.set();
+                },
+                Err(err) => emulate(p, emulated, Err(err)),
+            }
         },
         SYS_CLOSE => {
             p.set_nr(nr::CLOSE);
             p.set();
-            p.step()?;
+        },
+        SYS_DUP => {
+            let buf = p.pread(c as usize, d as usize).unwrap();
+            match convert_dup(p.pid, b, &buf) {
+                Ok(lnr) => {
+                    // Set up the new arguments, accept gets no address
+                    p.set_nr(lnr);
+                    p.set_c(0);
+                    p.set_d(0);
+                    p.set();
+                },
+                Err(err) => emulate(p, emulated, Err(err)),
+            }
+        },
+        SYS_DUP2 => {
+            let buf = p.pread(d as usize, e as usize).unwrap();
+            match convert_dup(p.pid, b, &buf) {
+                Ok(nr::DUP) => {
+                    p.set_nr(nr::DUP2);
+                    p.set();
+                },
+                Ok(lnr) => {
+                    // Create the new resource, which may block, and move it to
+                    // the requested file descriptor on exit. Accept gets no
+                    // address
+                    p.set_nr(lnr);
+                    p.set_c(0);
+                    p.set_d(0);
+                    p.set();
+                },
+                Err(err) => emulate(p, emulated, Err(err)),
+            }
         },
         SYS_EXIT => {
+            // Exiting does not use the stack or the scratch memory of the
+            // thread, so they can be unmapped first
+            if let Some(stack) = p.stack.take() {
+                p.inject(nr::MUNMAP, [stack.address as u64, stack.size as u64, 0, 0, 0, 0])?;
+            }
+            p.unmap_scratch()?;
+
             p.set_nr(nr::EXIT);
             p.set();
-            p.step()?;
         },
-        SYS_FCHMOD => {
-            p.set_nr(nr::FCHMOD);
-            p.set();
-            p.step()?;
+        SYS_FCHMOD => match upper_path(p, b) {
+            Some(Ok(lpath)) => {
+                // Change the copy in the writable layer by path
+                let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&lpath])?);
+
+                // Set up the new arguments
+                p.set_nr(nr::CHMOD);
+                p.set_b(addrs[0]);
+                p.set();
+            },
+            Some(Err(err)) => emulate(p, emulated, Err(err)),
+            None => {
+                p.set_nr(nr::FCHMOD);
+                p.set();
+            }
         },
-        SYS_FCHOWN => {
-            p.set_nr(nr::FCHOWN);
+        SYS_FCHOWN => match upper_path(p, b) {
+            Some(Ok(lpath)) => {
+                // Change the copy in the writable layer by path
+                let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&lpath])?);
+
+                // Set up the new arguments
+                p.set_nr(nr::CHOWN);
+                p.set_b(addrs[0]);
+                p.set();
+            },
+            Some(Err(err)) => emulate(p, emulated, Err(err)),
+            None => {
+                p.set_nr(nr::FCHOWN);
+                p.set();
+            }
+        },
+        SYS_FCNTL => if let Some(cmd) = convert_fcntl(c) {
+            // Redox uses O_CLOEXEC as the descriptor flag, and its own open flags
+            let arg = match c as usize {
+                F_SETFD => if d as usize & O_CLOEXEC > 0 {
+                    libc::FD_CLOEXEC as u64
+                } else {
+                    0
+                },
+                F_SETFL => convert_open(d).0,
+                _ => d,
+            };
+
+            // Set up the new arguments
+            p.set_nr(nr::FCNTL);
+            p.set_c(cmd as u64);
+            p.set_d(arg);
             p.set();
-            p.step()?;
+        } else {
+            emulate(p, emulated, Err(Error::new(EINVAL)));
         },
-        SYS_FCNTL => {
-            //TODO
-            p.set_nr(!0);
+        SYS_FEXEC => {
+            // Linux can only execute by path, so find the path of the file
+            let link = format!("/proc/{}/fd/{}", p.pid, b as libc::c_int);
+            match fs::read_link(link) {
+                Ok(path) => {
+                    // Read the arguments and environment, which are slices
+                    let mut strings = Vec::new();
+                    let mut lpath = path.as_os_str().as_bytes().to_vec();
+                    lpath.push(0);
+                    strings.push(lpath);
+                    for &(ptr, len) in [(c, d), (e, f)].iter() {
+                        for slice in p.read_type(ptr as *const [usize; 2], len as usize).unwrap() {
+                            let mut string = p.pread(slice[0], slice[1]).unwrap();
+                            string.push(0);
+                            strings.push(string);
+                        }
+                    }
+
+                    // Lay out the NULL terminated argv and envp arrays,
+                    // followed by the C strings
+                    let arrays_size = (strings.len() + 1) * mem::size_of::<usize>();
+                    let strings_size = strings.iter().map(|string| string.len()).sum::<usize>();
+                    let scratch = try_emulate!(p, emulated, p.scratch(arrays_size + strings_size)?);
+
+                    let mut addrs = Vec::with_capacity(strings.len());
+                    let mut addr = scratch + arrays_size;
+                    for string in strings.iter() {
+                        p.pwrite(addr, string).unwrap();
+                        addrs.push(addr);
+                        addr += string.len();
+                    }
+
+                    let args_len = d as usize;
+                    let mut argv = addrs[1..1 + args_len].to_vec();
+                    argv.push(0);
+                    let mut envp = addrs[1 + args_len..].to_vec();
+                    envp.push(0);
+                    let envp_addr = scratch + argv.len() * mem::size_of::<usize>();
+                    p.write_type(scratch as *mut usize, &argv).unwrap();
+                    p.write_type(envp_addr as *mut usize, &envp).unwrap();
+
+                    // Set up the new arguments. The new program will be
+                    // translated as well, see PTRACE_O_TRACEEXEC
+                    p.set_nr(nr::EXECVE);
+                    p.set_b(addrs[0] as u64);
+                    p.set_c(scratch as u64);
+                    p.set_d(envp_addr as u64);
+                    p.set();
+                },
+                Err(_) => emulate(p, emulated, Err(Error::new(EBADF))),
+            }
+        },
+        SYS_FMAP => if d as usize >= mem::size_of::<Map>() {
+            // Convert the map flags
+            let map = p.read_type(c as *const Map, 1).unwrap()[0];
+            let (prot, flags) = convert_map(b, map.flags);
+
+            // Set up the new arguments, letting Linux pick the address
+            p.set_nr(nr::MMAP);
+            p.set_b(0);
+            p.set_c(map.size as u64);
+            p.set_d(prot);
+            p.set_e(flags);
+            p.set_f(b);
+            p.set_g(map.offset as u64);
             p.set();
-            p.step()?;
+        } else {
+            emulate(p, emulated, Err(Error::new(EINVAL)));
+        },
+        SYS_FPATH => {
+            // Linux has no fpath, so read the link in procfs instead
+            let link = format!("/proc/{}/fd/{}", p.pid, b as libc::c_int);
+            let res = match fs::read_link(link) {
+                Ok(lpath) => {
+                    // Like Redox, truncate the path to the buffer size
+                    let mut rpath = convert_lpath(&p.schemes.borrow(), lpath.as_os_str().as_bytes());
+                    rpath.truncate(d as usize);
+                    p.pwrite(c as usize, &rpath).map(|()| rpath.len())
+                },
+                Err(_) => Err(Error::new(EBADF)),
+            };
+            emulate(p, emulated, res);
+        },
+        SYS_FRENAME => {
+            // Linux can only rename by path, so find the path of the file
+            let link = format!("/proc/{}/fd/{}", p.pid, b as libc::c_int);
+            match fs::read_link(link) {
+                Ok(old_path) => {
+                    let old_rpath = convert_lpath(&p.schemes.borrow(), old_path.as_os_str().as_bytes());
+                    let rpath = p.pread(c as usize, d as usize).unwrap();
+                    let rpath = canonicalize(&p.cwd.borrow(), &rpath);
+                    let renamed_opt = rename_path(&p.schemes.borrow(), &old_rpath, &rpath);
+                    if let Some(res) = renamed_opt {
+                        // The scheme renamed the file, for example by copying
+                        // it up and hiding the old name
+                        emulate(p, emulated, res);
+                    } else {
+                        // Convert the paths into C strings
+                        let mut old_lpath = old_path.as_os_str().as_bytes().to_vec();
+                        old_lpath.push(0);
+                        let lpath_res = convert_path(&p.schemes.borrow(), &rpath, Access::Write, false);
+                        match lpath_res {
+                            Ok(lpath) => {
+                                let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&old_lpath, &lpath])?);
+
+                                // Set up the new arguments
+                                p.set_nr(nr::RENAMEAT);
+                                p.set_b(libc::AT_FDCWD as u64);
+                                p.set_c(addrs[0]);
+                                p.set_d(libc::AT_FDCWD as u64);
+                                p.set_e(addrs[1]);
+                                p.set();
+                            },
+                            Err(err) => emulate(p, emulated, Err(err)),
+                        }
+                    }
+                },
+                Err(_) => emulate(p, emulated, Err(Error::new(EBADF))),
+            }
         },
         SYS_FSTAT => {
-            // Save the current stack page
-            let stack_addr = (p.regs.rsp as usize) & !(PAGE_SIZE - 1);
-            let stack_page = p.pread(stack_addr, PAGE_SIZE).unwrap();
+            // Get scratch memory for the Linux structure
+            let scratch = try_emulate!(p, emulated, p.scratch(mem::size_of::<libc::stat>())?);
 
             // Set up the new arguments
             p.set_nr(nr::FSTAT);
-            p.set_c(stack_addr as u64);
+            p.set_c(scratch as u64);
             p.set();
+        },
+        SYS_FSTATVFS => {
+            // Get scratch memory for the Linux structure
+            let scratch = try_emulate!(p, emulated, p.scratch(mem::size_of::<libc::statfs>())?);
 
-            // Call the system call
-            p.step()?;
-
-            // Read result
-            let lstat = p.pread(stack_addr, mem::size_of::<libc::stat>()).unwrap();
-            let rstat = convert_stat(&lstat);
-
-            // Restore the stack page
-            p.pwrite(stack_addr, &stack_page).unwrap();
-
-            // Write result
-            p.pwrite(c as usize, &rstat).unwrap();
-
-            // Restore the old arguments
-            p.set_c(c);
+            // Set up the new arguments
+            p.set_nr(nr::FSTATFS);
+            p.set_c(scratch as u64);
+            p.set();
         },
         SYS_FSYNC => {
             p.set_nr(nr::FSYNC);
             p.set();
-            p.step()?;
         },
-        SYS_FTRUNCATE => {
-            p.set_nr(nr::FTRUNCATE);
-            p.set();
-            p.step()?;
+        SYS_FTRUNCATE => match upper_path(p, b) {
+            Some(Ok(lpath)) => {
+                // Truncate the copy in the writable layer by path
+                let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&lpath])?);
+
+                // Set up the new arguments
+                p.set_nr(nr::TRUNCATE);
+                p.set_b(addrs[0]);
+                p.set();
+            },
+            Some(Err(err)) => emulate(p, emulated, Err(err)),
+            None => {
+                p.set_nr(nr::FTRUNCATE);
+                p.set();
+            }
         },
-        SYS_FUTEX => {
-            //TODO
-            p.set_nr(!0);
-            p.set();
-            p.step()?;
+        SYS_FUNMAP => {
+            let size_opt = p.maps.borrow().get(&(b as usize)).cloned();
+            if let Some(size) = size_opt {
+                // Set up the new arguments
+                p.set_nr(nr::MUNMAP);
+                p.set_c(size as u64);
+                p.set();
+            } else {
+                emulate(p, emulated, Err(Error::new(EINVAL)));
+            }
         },
-        SYS_FUTIMENS => {
-            //TODO
-            p.set_nr(!0);
+        SYS_FUTEX => if let Some(op) = convert_futex(c) {
+            // Get scratch memory for the Linux structure
+            let scratch = try_emulate!(p, emulated, p.scratch(mem::size_of::<libc::timespec>())?);
+
+            // Redox passes a TimeSpec pointer for timed waits, and requeues
+            // all remaining waiters, while Linux takes a count for requeue
+            let val2 = match op {
+                linux::FUTEX_WAIT => if e != 0 {
+                    let rtimespec = p.read_type(e as *const TimeSpec, 1).unwrap();
+                    let ltimespec = convert_rtimespec(&rtimespec[0]);
+                    p.write_type(scratch as *mut libc::timespec, &[ltimespec]).unwrap();
+                    scratch as u64
+                } else {
+                    0
+                },
+                linux::FUTEX_REQUEUE => i32::max_value() as u64,
+                _ => e,
+            };
+
+            // Set up the new arguments
+            p.set_nr(nr::FUTEX);
+            p.set_c(op as u64);
+            p.set_e(val2);
             p.set();
-            p.step()?;
+        } else {
+            emulate(p, emulated, Err(Error::new(EINVAL)));
+        },
+        SYS_FUTIMENS => match upper_path(p, b).unwrap_or(Ok(Vec::new())) {
+            Ok(lpath) => {
+                // Get scratch memory for the Linux structures, followed by
+                // the path of the copy in the writable layer, if any
+                let size = 2 * mem::size_of::<libc::timespec>();
+                let scratch = try_emulate!(p, emulated, p.scratch(size + lpath.len())?);
+
+                // Times that are not given are left unchanged
+                let count = cmp::min(d as usize / mem::size_of::<TimeSpec>(), 2);
+                let rtimes = p.read_type(c as *const TimeSpec, count).unwrap();
+                let mut ltimes = [libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT }; 2];
+                for (ltime, rtime) in ltimes.iter_mut().zip(rtimes.iter()) {
+                    *ltime = convert_rtime(rtime);
+                }
+                p.write_type(scratch as *mut libc::timespec, &ltimes).unwrap();
+
+                // Set up the new arguments, a NULL path means the fd itself
+                p.set_nr(nr::UTIMENSAT);
+                if lpath.is_empty() {
+                    p.set_c(0);
+                } else {
+                    p.pwrite(scratch + size, &lpath).unwrap();
+                    p.set_b(libc::AT_FDCWD as u64);
+                    p.set_c((scratch + size) as u64);
+                }
+                p.set_d(scratch as u64);
+                p.set_e(0);
+                p.set();
+            },
+            Err(err) => emulate(p, emulated, Err(err)),
         },
         SYS_GETCWD => {
-            //TODO
-            p.set_nr(!0);
-            p.set();
-            p.step()?;
+            // The working directory is only tracked by rine, so skip the
+            // system call and write the Redox path to the buffer instead
+            let cwd = p.cwd.borrow().clone();
+            let res = if cwd.len() <= c as usize {
+                p.pwrite(b as usize, &cwd).map(|()| cwd.len())
+            } else {
+                Err(Error::new(ERANGE))
+            };
+            emulate(p, emulated, res);
         },
         SYS_GETEGID => {
             p.set_nr(nr::GETEGID);
             p.set();
-            p.step()?;
         },
         SYS_GETEUID => {
             p.set_nr(nr::GETEUID);
             p.set();
-            p.step()?;
         },
         SYS_GETGID => {
             p.set_nr(nr::GETGID);
             p.set();
-            p.step()?;
         },
         SYS_GETPGID => {
             p.set_nr(nr::GETPGID);
             p.set();
-            p.step()?;
         },
         SYS_GETPID => {
             p.set_nr(nr::GETPID);
             p.set();
-            p.step()?;
         },
         SYS_GETPPID => {
             p.set_nr(nr::GETPPID);
             p.set();
-            p.step()?;
         },
         SYS_GETUID => {
             p.set_nr(nr::GETUID);
             p.set();
-            p.step()?;
         },
         SYS_IOPL => {
             p.set_nr(nr::IOPL);
             p.set();
-            p.step()?;
         },
         SYS_KILL => {
-            p.set_nr(nr::KILL);
-            p.set();
-            p.step()?;
+            // Signal 0 only checks that the process exists
+            let lsig_opt = if c == 0 {
+                Some(0)
+            } else {
+                convert_signal(c as usize)
+            };
+
+            if let Some(lsig) = lsig_opt {
+                p.set_nr(nr::KILL);
+                p.set_c(lsig as u64);
+                p.set();
+            } else {
+                emulate(p, emulated, Err(Error::new(EINVAL)));
+            }
+        },
+        SYS_LINK => {
+            // Unlike other paths, both paths are NUL-terminated strings
+            let rpaths_res = p.pread_cstr(b as usize).and_then(|old_rpath| {
+                p.pread_cstr(c as usize).map(|rpath| (old_rpath, rpath))
+            });
+
+            // Convert the paths into C strings. Like overlayfs, the old file
+            // is copied up, so that the new name does not share the inode of
+            // a read-only layer
+            let lpaths_res = rpaths_res.and_then(|(old_rpath, rpath)| {
+                let schemes = p.schemes.borrow();
+                let cwd = p.cwd.borrow();
+                convert_path(&schemes, &canonicalize(&cwd, &old_rpath), Access::Write, false).and_then(|old_lpath| {
+                    convert_path(&schemes, &canonicalize(&cwd, &rpath), Access::Write, false).map(|lpath| (old_lpath, lpath))
+                })
+            });
+            match lpaths_res {
+                Ok((old_lpath, lpath)) => {
+                    let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&old_lpath, &lpath])?);
+
+                    // Set up the new arguments
+                    p.set_nr(nr::LINK);
+                    p.set_b(addrs[0]);
+                    p.set_c(addrs[1]);
+                    p.set();
+                },
+                Err(err) => emulate(p, emulated, Err(err)),
+            }
         },
         SYS_LSEEK => {
             p.set_nr(nr::LSEEK);
             p.set();
-            p.step()?;
         },
-        SYS_OPEN => {
-            // Convert the path into a C string
-            let rpath = p.pread(b as usize, c as usize).unwrap();
-            let lpath = convert_path(&rpath);
-
-            if lpath.len() > PAGE_SIZE {
-                panic!("path larger than PAGE_SIZE {}", PAGE_SIZE);
-            }
-
-            // Save the current stack page
-            let stack_addr = (p.regs.rsp as usize) & !(PAGE_SIZE - 1);
-            let stack_page = p.pread(stack_addr, PAGE_SIZE).unwrap();
-
-            // Write the path to the stack
-            p.pwrite(stack_addr, &lpath).unwrap();
+        SYS_MPROTECT => {
+            // Set up the new arguments, other flags have no meaning here
+            p.set_nr(nr::MPROTECT);
+            p.set_d(convert_prot(d as usize));
+            p.set();
+        },
+        SYS_NANOSLEEP => {
+            // Get scratch memory for the requested and remaining Linux times
+            let size = mem::size_of::<libc::timespec>();
+            let scratch = try_emulate!(p, emulated, p.scratch(2 * size)?);
 
-            // Convert the open flags
-            let (oflag, mode) = convert_open(d);
+            let rtimespec = p.read_type(b as *const TimeSpec, 1).unwrap();
+            let ltimespec = convert_rtimespec(&rtimespec[0]);
+            p.write_type(scratch as *mut libc::timespec, &[ltimespec]).unwrap();
 
             // Set up the new arguments
-            p.set_nr(nr::OPEN);
-            p.set_b(stack_addr as u64);
-            p.set_c(oflag);
-            p.set_d(mode);
+            p.set_nr(nr::NANOSLEEP);
+            p.set_b(scratch as u64);
+            p.set_c(if c != 0 { (scratch + size) as u64 } else { 0 });
             p.set();
+        },
+        SYS_OPEN => {
+            // Convert the path into a C string
+            let rpath = p.pread(b as usize, c as usize).unwrap();
+            let rpath = canonicalize(&p.cwd.borrow(), &rpath);
+            // Files that are changed must be in a writable layer
+            let access = if d as usize & (O_ACCMODE & !O_RDONLY) > 0 || d as usize & (O_CREAT | O_TRUNC) > 0 {
+                Access::Write
+            } else {
+                Access::Read
+            };
+            // Like Linux, O_NOFOLLOW does not follow a symbolic link at the end
+            let follow = d as usize & O_NOFOLLOW == 0;
+            let lpath_opt = convert_path_opt(&p.schemes.borrow(), &rpath, access, follow);
+            match lpath_opt {
+                // Directories are listed by their scheme, which merges the
+                // layers of a union
+                Some(Ok(ref lpath)) if access == Access::Read && d as usize & O_STAT == 0 && is_dir(lpath) => {
+                    let res = open_file(p, &rpath, d)?;
+                    emulate(p, emulated, res);
+                },
+                Some(Ok(lpath)) => {
+                    let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&lpath])?);
 
-            // Call the system call
-            p.step()?;
-
-            // Restore the stack page
-            p.pwrite(stack_addr, &stack_page).unwrap();
+                    // Convert the open flags
+                    let (oflag, mode) = convert_open(d);
 
-            // Restore the old arguments
-            p.set_b(b);
-            p.set_c(c);
-            p.set_d(d);
+                    // Set up the new arguments
+                    p.set_nr(nr::OPEN);
+                    p.set_b(addrs[0]);
+                    p.set_c(oflag);
+                    p.set_d(mode);
+                    p.set();
+                },
+                Some(Err(err)) => emulate(p, emulated, Err(err)),
+                None => {
+                    // Schemes without Linux paths are emulated
+                    let res = open_file(p, &rpath, d)?;
+                    emulate(p, emulated, res);
+                }
+            }
         },
         SYS_PIPE2 => {
-            // Save the current stack page
-            let stack_addr = (p.regs.rsp as usize) & !(PAGE_SIZE - 1);
-            let stack_page = p.pread(stack_addr, PAGE_SIZE).unwrap();
+            // Get scratch memory for the Linux structure
+            let scratch = try_emulate!(p, emulated, p.scratch(mem::size_of::<[libc::c_int; 2]>())?);
 
             // Convert open flags
             let (oflag, _mode) = convert_open(c);
 
             // Set up the new arguments
             p.set_nr(nr::PIPE2);
-            p.set_b(stack_addr as u64);
+            p.set_b(scratch as u64);
             p.set_c(oflag);
             p.set();
-
-            // Call the system call
-            p.step()?;
-
-            // Read the pipe fds
-            let lpipe = p.read_type(stack_addr as *const libc::c_int, 2).unwrap();
-            let rpipe = convert_pipe(&lpipe);
-
-            // Restore the stack page
-            p.pwrite(stack_addr, &stack_page).unwrap();
-
-            // Write the pipe fds
-            p.write_type(b as *mut usize, &rpipe).unwrap();
-
-            // Restore the old arguments
-            p.set_b(b);
-            p.set_c(c);
         },
         SYS_READ => {
             p.set_nr(nr::READ);
             p.set();
-            p.step()?;
+        },
+        SYS_RMDIR => {
+            let rpath = p.pread(b as usize, c as usize).unwrap();
+            let rpath = canonicalize(&p.cwd.borrow(), &rpath);
+            let removed_opt = remove_path(&p.schemes.borrow(), &rpath, true);
+            if let Some(res) = removed_opt {
+                // The scheme removed the file, for example with a whiteout
+                emulate(p, emulated, res);
+            } else {
+                // Convert the path into a C string
+                let lpath_res = convert_path(&p.schemes.borrow(), &rpath, Access::Write, false);
+                match lpath_res {
+                    Ok(lpath) => {
+                        let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&lpath])?);
+
+                        // Set up the new arguments
+                        p.set_nr(nr::RMDIR);
+                        p.set_b(addrs[0]);
+                        p.set();
+                    },
+                    Err(err) => emulate(p, emulated, Err(err)),
+                }
+            }
         },
         SYS_SETPGID => {
             p.set_nr(nr::SETPGID);
             p.set();
-            p.step()?;
         },
         SYS_SETREGID => {
             p.set_nr(nr::SETREGID);
             p.set();
-            p.step()?;
         },
         SYS_SETREUID => {
             p.set_nr(nr::SETREUID);
             p.set();
-            p.step()?;
+        },
+        SYS_SIGACTION => {
+            let sig = b as usize;
+            let res = if convert_signal(sig).is_none() || (c != 0 && (sig == SIGKILL || sig == SIGSTOP)) {
+                Err(Error::new(EINVAL))
+            } else {
+                // Signals are delivered by rine, so Linux keeps the default
+                // actions and the Redox actions are only recorded
+                if d != 0 {
+                    let (oldact, _restorer) = p.sigactions.borrow().get(&sig).cloned().unwrap_or_default();
+                    p.write_type(d as *mut SigAction, &[oldact]).unwrap();
+                }
+
+                if c != 0 {
+                    let act = p.read_type(c as *const SigAction, 1).unwrap();
+                    p.sigactions.borrow_mut().insert(sig, (act[0], e as usize));
+                }
+
+                Ok(0)
+            };
+            emulate(p, emulated, res);
+        },
+        SYS_SIGPROCMASK => {
+            let how_opt = match b as usize {
+                SIG_BLOCK => Some(libc::SIG_BLOCK),
+                SIG_UNBLOCK => Some(libc::SIG_UNBLOCK),
+                SIG_SETMASK => Some(libc::SIG_SETMASK),
+                _ => None,
+            };
+
+            if let Some(how) = how_opt {
+                // Get scratch memory for the new and old Linux sets
+                let scratch = try_emulate!(p, emulated, p.scratch(2 * mem::size_of::<u64>())?);
+                if c != 0 {
+                    let set = p.read_type(c as *const [u64; 2], 1).unwrap();
+                    p.write_type(scratch as *mut u64, &[convert_sigset(&set[0])]).unwrap();
+                }
+
+                // Set up the new arguments
+                p.set_nr(nr::RT_SIGPROCMASK);
+                p.set_b(how as u64);
+                p.set_c(if c != 0 { scratch as u64 } else { 0 });
+                p.set_d(if d != 0 { (scratch + mem::size_of::<u64>()) as u64 } else { 0 });
+                p.set_e(mem::size_of::<u64>() as u64);
+                p.set();
+            } else {
+                emulate(p, emulated, Err(Error::new(EINVAL)));
+            }
+        },
+        SYS_SIGRETURN => {
+            // The frame is restored on exit, so the result is not used
+            let res = if p.sigframes.is_empty() {
+                Err(Error::new(EINVAL))
+            } else {
+                Ok(0)
+            };
+            emulate(p, emulated, res);
         },
         SYS_UMASK => {
             p.set_nr(nr::UMASK);
             p.set();
-            p.step()?;
+        },
+        SYS_UNLINK => {
+            let rpath = p.pread(b as usize, c as usize).unwrap();
+            let rpath = canonicalize(&p.cwd.borrow(), &rpath);
+            let removed_opt = remove_path(&p.schemes.borrow(), &rpath, false);
+            if let Some(res) = removed_opt {
+                // The scheme removed the file, for example with a whiteout
+                emulate(p, emulated, res);
+            } else {
+                // Convert the path into a C string
+                let lpath_res = convert_path(&p.schemes.borrow(), &rpath, Access::Write, false);
+                match lpath_res {
+                    Ok(lpath) => {
+                        let addrs = try_emulate!(p, emulated, scratch_paths(p, &[&lpath])?);
+
+                        // Set up the new arguments
+                        p.set_nr(nr::UNLINK);
+                        p.set_b(addrs[0]);
+                        p.set();
+                    },
+                    Err(err) => emulate(p, emulated, Err(err)),
+                }
+            }
+        },
+        SYS_WAITPID => {
+            // Get scratch memory for the Linux status, which is a c_int
+            let scratch = try_emulate!(p, emulated, p.scratch(mem::size_of::<libc::c_int>())?);
+
+            // Set up the new arguments. This blocks in the tracee, not in
+            // rine, whose event loop keeps serving other threads meanwhile.
+            // Redox waits for any child with pid 0, Linux only for children
+            // in the same process group
+            p.set_nr(nr::WAIT4);
+            if b == 0 {
+                p.set_b(-1i64 as u64);
+            }
+            p.set_c(if c != 0 { scratch as u64 } else { 0 });
+            p.set_d(convert_wait(d));
+            p.set_e(0);
+            p.set();
         },
         SYS_WRITE => {
             p.set_nr(nr::WRITE);
             p.set();
-            p.step()?;
         },
         SYS_YIELD => {
             p.set_nr(nr::SCHED_YIELD);
             p.set();
-            p.step()?;
         },
         _ => {
             p.set_nr(!0);
             p.set();
-            p.step()?;
         }
     }
 
-    p.get();
+    Ok(())
+}
+
+unsafe fn handle_exit(p: &mut Process, call: Syscall) -> result::Result<(), i32> {
+    let Syscall { a, b, c, d, e, f, g, emulated, clone_stack } = call;
+
+    if a as usize == SYS_FEXEC && p.result().is_ok() {
+        // The old program was replaced, so there is nothing to restore
+        debug!("fexec({}) = Ok(0)", b);
+        return Ok(());
+    }
+
+    if a as usize == SYS_SIGRETURN && emulated.as_ref().map_or(false, |res| res.is_ok()) {
+        // Return to where the thread was interrupted by the signal, with the
+        // signal mask it had then
+        let frame = p.sigframes.pop().unwrap();
+        p.regs = frame.regs;
+        p.set();
+        p.set_fp(&frame.fpregs);
+        p.set_mask(frame.mask);
+        debug!("sigreturn() = {:#x}", p.regs.rip);
+        return Ok(());
+    }
+
+    // Whether the Redox system call is executed again
+    let mut restart = false;
+
+    match a as usize {
+        SYS_CHDIR => if p.result().is_ok() {
+            // Update the working directory on success
+            let rpath = p.pread(b as usize, c as usize).unwrap();
+            let cwd = canonicalize(&p.cwd.borrow(), &rpath);
+            *p.cwd.borrow_mut() = cwd;
+        },
+        SYS_CLOCK_GETTIME => if p.result().is_ok() {
+            // Read result
+            let scratch = p.scratch_address();
+            let ltimespec = p.pread(scratch, mem::size_of::<libc::timespec>()).unwrap();
+            let rtimespec = convert_timespec(&ltimespec);
+
+            // Write result
+            p.pwrite(c as usize, &rtimespec).unwrap();
+        },
+        SYS_CLONE => if let Some(stack) = clone_stack {
+            // Give the parent back its frame pointer. Linux leaves the stack
+            // pointer of the child in rsi
+            let rbp = p.regs.rbp as usize;
+            if rbp >= stack.address && rbp < stack.address + stack.size {
+                p.regs.rbp = (rbp - p.regs.rsi as usize + p.regs.rsp as usize) as u64;
+            }
+        },
+        SYS_FCNTL => if let Ok(value) = p.result() {
+            // Convert the flags back to Redox flags
+            match c as usize {
+                F_GETFD => p.set_result(Ok(if value & libc::FD_CLOEXEC as usize > 0 {
+                    O_CLOEXEC
+                } else {
+                    0
+                })),
+                F_GETFL => p.set_result(Ok(convert_lopen(value as u64) as usize)),
+                _ => (),
+            }
+        },
+        SYS_DUP2 => if let Ok(fd) = p.result() {
+            if p.regs.orig_rax as usize != nr::DUP2 && fd != c as usize {
+                // Move the new resource to the requested file descriptor
+                let result = p.inject_exit(nr::DUP2, [fd as u64, c, 0, 0, 0, 0])?;
+                p.inject_exit(nr::CLOSE, [fd as u64, 0, 0, 0, 0, 0])?;
+                p.regs.rax = result;
+            }
+
+            // Linux closed the placeholder of the new file descriptor
+            if p.result().is_ok() {
+                let _ = close_file(p, c as usize);
+            }
+        },
+        SYS_FMAP => if let Ok(address) = p.result() {
+            // Remember the size, as funmap only passes the address
+            let map = p.read_type(c as *const Map, 1).unwrap()[0];
+            p.maps.borrow_mut().insert(address, map.size);
+        },
+        SYS_FSTAT => if p.result().is_ok() {
+            // Read result
+            let scratch = p.scratch_address();
+            let lstat = p.pread(scratch, mem::size_of::<libc::stat>()).unwrap();
+            let rstat = convert_stat(&lstat);
+
+            // Write result
+            p.pwrite(c as usize, &rstat).unwrap();
+        },
+        SYS_FSTATVFS => if p.result().is_ok() {
+            // Read result
+            let scratch = p.scratch_address();
+            let lstatfs = p.pread(scratch, mem::size_of::<libc::statfs>()).unwrap();
+            let rstatvfs = convert_statvfs(&lstatfs);
+
+            // Write result
+            p.pwrite(c as usize, &rstatvfs).unwrap();
+        },
+        SYS_FUNMAP => if p.result().is_ok() {
+            // Forget the mapping on success
+            p.maps.borrow_mut().remove(&(b as usize));
+        },
+        SYS_NANOSLEEP => {
+            let lerrno = -(p.regs.rax as i64) as libc::c_int;
+
+            // Write the remaining time if the sleep was interrupted. It is not
+            // restarted, as that would sleep for the whole time again
+            if c != 0 && (lerrno == libc::EINTR || lerrno == linux::ERESTART_RESTARTBLOCK) {
+                let scratch = p.scratch_address() + mem::size_of::<libc::timespec>();
+                let ltimespec = p.pread(scratch, mem::size_of::<libc::timespec>()).unwrap();
+                let rtimespec = convert_timespec(&ltimespec);
+                p.write_type(c as *mut TimeSpec, &[rtimespec]).unwrap();
+            }
+        },
+        SYS_PIPE2 => if p.result().is_ok() {
+            // Read the pipe fds
+            let scratch = p.scratch_address();
+            let lpipe = p.read_type(scratch as *const libc::c_int, 2).unwrap();
+            let rpipe = convert_pipe(&lpipe);
+
+            // Write the pipe fds
+            p.write_type(b as *mut usize, &rpipe).unwrap();
+        },
+        SYS_SIGPROCMASK => if p.result().is_ok() && d != 0 {
+            // Read the old Linux set
+            let scratch = p.scratch_address() + mem::size_of::<u64>();
+            let lset = p.read_type(scratch as *const u64, 1).unwrap();
+
+            // Write the old Redox set
+            p.write_type(d as *mut [u64; 2], &[convert_lsigset(lset[0])]).unwrap();
+        },
+        SYS_READ => if p.regs.orig_rax as usize == nr::NANOSLEEP {
+            // The tracee waited for an event queue. Like Redox, a signal
+            // interrupts the read, and otherwise it is tried again
+            let lerrno = -(p.regs.rax as i64) as libc::c_int;
+            restart = lerrno != libc::EINTR && lerrno != linux::ERESTART_RESTARTBLOCK;
+        },
+        SYS_WAITPID => if p.result().is_ok() && c != 0 {
+            // Read the Linux status
+            let scratch = p.scratch_address();
+            let lstatus = p.read_type(scratch as *const libc::c_int, 1).unwrap();
+            let rstatus = convert_wstatus(lstatus[0]);
+
+            // Write the Redox status
+            p.write_type(c as *mut usize, &[rstatus]).unwrap();
+        },
+        _ => ()
+    }
+
+    // Restore the Redox system call and its arguments. If Linux restarts the
+    // system call, for example after a signal, it will be translated again
+    p.set_a(a);
+    p.set_b(b);
+    p.set_c(c);
+    p.set_d(d);
+    p.set_e(e);
+    p.set_f(f);
+    p.set_g(g);
+
+    if let Some(res) = emulated {
+        p.set_result(res);
+    } else {
+        // Linux returns errors as -errno, which need to be converted. Errors
+        // above 511 are internal to Linux and used for restarting system
+        // calls, so they must not be changed. The exception is
+        // ERESTART_RESTARTBLOCK, which continues with restart_syscall. That
+        // is not a Redox system call, so like Redox, return EINTR instead
+        let lerrno = -(p.regs.rax as i64);
+        if lerrno == linux::ERESTART_RESTARTBLOCK as i64 {
+            p.set_result(Err(Error::new(EINTR)));
+        } else if lerrno >= 1 && lerrno < 512 {
+            let errno = errno::convert_errno(lerrno as i32).unwrap_or_else(|| {
+                debug!("unmapped Linux errno {}", lerrno);
+                lerrno as i32
+            });
+            p.set_result(Err(Error::new(errno)));
+        }
+    }
+
+    if restart {
+        // Go back to the syscall instruction with the Redox system call
+        p.regs.rip -= 2;
+        p.regs.rax = a;
+    }
+
+    p.set();
 
     let res = p.result();
+    let format_call = true;
     if format_call {
         debug!("{} = {:?} {:x?}", debug::format_call(
-            &mut p,
+            p,
             a as usize,
             b as usize,
             c as usize,
@@ -1,29 +1,258 @@
 use libc;
-use std::mem;
+use sc::nr;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::{cmp, env, fs, mem};
+use std::os::unix::ffi::OsStrExt;
 use syscall;
 
+use super::PAGE_SIZE;
+use super::linux;
+use super::scheme::Schemes;
+
+/// A Redox system call that has been translated, waiting for Linux to return
+pub struct Syscall {
+    pub a: u64,
+    pub b: u64,
+    pub c: u64,
+    pub d: u64,
+    pub e: u64,
+    pub f: u64,
+    /// Value of r9, which Linux uses for a sixth argument
+    pub g: u64,
+    /// Result of a system call that was handled by rine instead of Linux
+    pub emulated: Option<syscall::Result<usize>>,
+    /// Stack that was mapped for the child of SYS_CLONE
+    pub clone_stack: Option<Stack>,
+}
+
+/// Memory mapped by rine as the stack of a thread cloned with CLONE_VM
+#[derive(Clone, Copy)]
+pub struct Stack {
+    pub address: usize,
+    pub size: usize,
+}
+
+/// A file descriptor whose resource is emulated by a scheme in rine. Linux
+/// has a placeholder file descriptor with the same number
+#[derive(Clone)]
+pub struct File {
+    pub scheme: Vec<u8>,
+    pub id: usize,
+}
+
+/// Registers and Linux signal mask of a thread that were saved when a signal
+/// handler was called, restored by SYS_SIGRETURN
+pub struct SignalFrame {
+    pub regs: libc::user_regs_struct,
+    pub fpregs: libc::user_fpregs_struct,
+    pub mask: u64,
+}
+
 pub struct Process{
-    pid: libc::pid_t,
-    pub regs: libc::user_regs_struct
+    pub pid: libc::pid_t,
+    pub regs: libc::user_regs_struct,
+    /// Redox-style working directory, including the scheme. Shared with
+    /// threads cloned with CLONE_FS
+    pub cwd: Rc<RefCell<Vec<u8>>>,
+    /// Sizes of memory mapped with fmap, indexed by address. Shared with
+    /// threads cloned with CLONE_VM
+    pub maps: Rc<RefCell<BTreeMap<usize, usize>>>,
+    /// Schemes that paths are resolved in, shared by all threads
+    pub schemes: Rc<RefCell<Schemes>>,
+    /// Emulated file descriptors, other file descriptors are Linux file
+    /// descriptors with the same number. Shared with threads cloned with
+    /// CLONE_FILES
+    pub files: Rc<RefCell<BTreeMap<usize, File>>>,
+    /// Redox signal actions and their restorers, indexed by Redox signal.
+    /// Shared with threads cloned with CLONE_SIGHAND
+    pub sigactions: Rc<RefCell<BTreeMap<usize, (syscall::SigAction, usize)>>>,
+    /// Signal handlers that this thread is currently in
+    pub sigframes: Vec<SignalFrame>,
+    /// Stack that rine mapped for this thread, unmapped when it exits
+    pub stack: Option<Stack>,
+    /// Linux signals that arrived while rine was injecting system calls
+    pending: Vec<libc::c_int>,
+    /// Address and size of memory mapped by rine for passing arguments
+    scratch: Option<(usize, usize)>,
+    /// System call that this thread is currently in
+    pub syscall: Option<Syscall>,
 }
 
 impl Process {
-    pub unsafe fn new(pid: libc::pid_t) -> Process {
+    /// Create the state of the first process, using the given schemes
+    pub unsafe fn new(pid: libc::pid_t, schemes: Schemes) -> Process {
+        // The tracee starts in the root if the working directory is outside
+        let cwd = env::current_dir().ok()
+            .and_then(|dir| schemes.rpath(dir.as_os_str().as_bytes()))
+            .unwrap_or_else(|| b"file:/".to_vec());
+
         Process {
             pid,
-            regs: mem::zeroed()
+            regs: mem::zeroed(),
+            cwd: Rc::new(RefCell::new(cwd)),
+            maps: Rc::new(RefCell::new(BTreeMap::new())),
+            schemes: Rc::new(RefCell::new(schemes)),
+            files: Rc::new(RefCell::new(BTreeMap::new())),
+            sigactions: Rc::new(RefCell::new(BTreeMap::new())),
+            sigframes: Vec::new(),
+            stack: None,
+            pending: Vec::new(),
+            scratch: None,
+            syscall: None,
+        }
+    }
+
+    /// Create the state for a new thread or process that was cloned by this
+    /// one, sharing state depending on the Redox clone flags
+    pub unsafe fn spawn(&self, pid: libc::pid_t) -> Process {
+        let (flags, stack) = match self.syscall {
+            Some(ref call) if call.a as usize == syscall::SYS_CLONE => (call.b as usize, call.clone_stack),
+            _ => (0, None),
+        };
+
+        let cwd = if flags & syscall::CLONE_FS > 0 {
+            self.cwd.clone()
+        } else {
+            Rc::new(RefCell::new(self.cwd.borrow().clone()))
+        };
+
+        let maps = if flags & syscall::CLONE_VM > 0 {
+            self.maps.clone()
+        } else {
+            Rc::new(RefCell::new(self.maps.borrow().clone()))
+        };
+
+        let files = if flags & syscall::CLONE_FILES > 0 {
+            self.files.clone()
+        } else {
+            // The new process gets its own emulated resources, like it gets
+            // its own copies of the Linux file descriptors
+            let mut schemes = self.schemes.borrow_mut();
+            let mut files = BTreeMap::new();
+            for (&fd, file) in self.files.borrow().iter() {
+                if let Ok(id) = schemes.call(&file.scheme, |scheme| scheme.dup(file.id, b"")) {
+                    files.insert(fd, File {
+                        scheme: file.scheme.clone(),
+                        id,
+                    });
+                }
+            }
+            Rc::new(RefCell::new(files))
+        };
+
+        let sigactions = if flags & syscall::CLONE_SIGHAND > 0 {
+            self.sigactions.clone()
+        } else {
+            Rc::new(RefCell::new(self.sigactions.borrow().clone()))
+        };
+
+        Process {
+            pid,
+            regs: mem::zeroed(),
+            cwd,
+            maps,
+            schemes: self.schemes.clone(),
+            files,
+            sigactions,
+            sigframes: Vec::new(),
+            stack,
+            pending: Vec::new(),
+            // Threads sharing memory cannot share the scratch memory
+            scratch: None,
+            syscall: None,
+        }
+    }
+
+    /// Forget the state of the old program after it was replaced by execve
+    pub fn exec(&mut self) {
+        self.maps = Rc::new(RefCell::new(BTreeMap::new()));
+        self.scratch = None;
+        self.sigframes.clear();
+        // The stack was mapped in the old memory, which may still be used by
+        // the parent after a vfork
+        self.stack = None;
+
+        // Emulated file descriptors are kept, unless Linux closed their
+        // placeholder because of O_CLOEXEC
+        let mut closed = Vec::new();
+        for (&fd, file) in self.files.borrow().iter() {
+            if fs::symlink_metadata(format!("/proc/{}/fd/{}", self.pid, fd)).is_err() {
+                closed.push((fd, file.clone()));
+            }
+        }
+        for (fd, file) in closed {
+            self.files.borrow_mut().remove(&fd);
+            let _ = self.schemes.borrow_mut().call(&file.scheme, |scheme| scheme.close(file.id));
+        }
+
+        // Handlers are gone, but ignored signals stay ignored
+        let sigactions = self.sigactions.borrow().iter()
+            .filter(|&(_sig, &(action, _restorer))| action.sa_handler as usize == syscall::SIG_IGN)
+            .map(|(&sig, &action)| (sig, action))
+            .collect();
+        self.sigactions = Rc::new(RefCell::new(sigactions));
+    }
+
+    /// Close the emulated resources of a thread that exited, if no other
+    /// thread shares them
+    pub fn exit(&mut self) {
+        if Rc::strong_count(&self.files) == 1 {
+            let mut schemes = self.schemes.borrow_mut();
+            for (_fd, file) in self.files.borrow().iter() {
+                let _ = schemes.call(&file.scheme, |scheme| scheme.close(file.id));
+            }
+            self.files.borrow_mut().clear();
         }
     }
 
+    /// Continue until the next system call stop, which is handled by the
+    /// caller. The Linux `signal` is delivered if it is not zero, and signals
+    /// that arrived during system call injection are raised again
+    pub unsafe fn resume(&mut self, signal: libc::c_int) -> Result<(), i32> {
+        for pending in self.pending.drain(..) {
+            libc::syscall(libc::SYS_tkill, self.pid, pending);
+        }
+
+        self.ptrace_syscall(signal)
+    }
+
+    /// Keep a thread in a group-stop, while letting Linux report the stop to
+    /// its parent and wake it up with SIGCONT
+    pub unsafe fn listen(&mut self) -> Result<(), i32> {
+        if libc::ptrace(libc::PTRACE_LISTEN, self.pid, 0, 0) < 0 {
+            libc::perror(b"PTRACE_LISTEN\0".as_ptr() as *const _);
+            return Err(1);
+        }
+
+        Ok(())
+    }
+
+    unsafe fn ptrace_syscall(&self, signal: libc::c_int) -> Result<(), i32> {
+        if libc::ptrace(libc::PTRACE_SYSCALL, self.pid, 0, signal) < 0 {
+            libc::perror(b"PTRACE_SYSCALL\0".as_ptr() as *const _);
+            return Err(1);
+        }
+
+        Ok(())
+    }
+
+    /// Check if the thread is in a signal-delivery-stop, as opposed to a
+    /// group-stop, which has no signal to deliver
+    pub unsafe fn signal_delivery(&self) -> bool {
+        let mut siginfo: libc::siginfo_t = mem::zeroed();
+        libc::ptrace(libc::PTRACE_GETSIGINFO, self.pid, 0, &mut siginfo) >= 0
+    }
+
+    /// Continue until the next system call stop of this thread, ignoring
+    /// other threads
     pub unsafe fn step(&mut self) -> Result<(), i32> {
         loop {
-            if libc::ptrace(libc::PTRACE_SYSCALL, self.pid, 0, 0) < 0 {
-                libc::perror(b"PTRACE_SYSCALL\0".as_ptr() as *const _);
-                return Err(1);
-            }
+            self.ptrace_syscall(0)?;
 
             let mut status = 0;
-            if libc::waitpid(self.pid, &mut status, 0) < 0 {
+            if libc::waitpid(self.pid, &mut status, libc::__WALL) < 0 {
                 libc::perror(b"waitpid\0".as_ptr() as *const _);
                 return Err(1);
             }
@@ -35,9 +264,16 @@ impl Process {
             } else if libc::WIFSTOPPED(status) {
                 let signal = libc::WSTOPSIG(status);
                 trace!("  STOPPED {}", signal);
+                // Signals cannot be handled in the middle of an injected
+                // system call, so they are raised again on resume. Other
+                // stops, like group-stops, are continued
+                if status >> 16 == 0 && self.signal_delivery() {
+                    self.pending.push(signal);
+                }
             } else if libc::WIFSIGNALED(status) {
                 let signal = libc::WTERMSIG(status);
                 trace!("  SIGNALED {}", signal);
+                return Err(128 + signal);
             } else if libc::WIFEXITED(status) {
                 let exit_status = libc::WEXITSTATUS(status);
                 trace!("  EXIT {}", exit_status);
@@ -58,6 +294,34 @@ impl Process {
         }
     }
 
+    pub unsafe fn get_fp(&mut self) -> libc::user_fpregs_struct {
+        let mut fpregs: libc::user_fpregs_struct = mem::zeroed();
+        if libc::ptrace(libc::PTRACE_GETFPREGS, self.pid, 0, &mut fpregs) < 0 {
+            libc::perror(b"PTRACE_GETFPREGS\0".as_ptr() as *const _);
+        }
+        fpregs
+    }
+
+    pub unsafe fn set_fp(&mut self, fpregs: &libc::user_fpregs_struct) {
+        if libc::ptrace(libc::PTRACE_SETFPREGS, self.pid, 0, fpregs) < 0 {
+            libc::perror(b"PTRACE_SETFPREGS\0".as_ptr() as *const _);
+        }
+    }
+
+    pub unsafe fn get_mask(&mut self) -> u64 {
+        let mut mask = 0u64;
+        if libc::ptrace(linux::PTRACE_GETSIGMASK, self.pid, mem::size_of::<u64>(), &mut mask) < 0 {
+            libc::perror(b"PTRACE_GETSIGMASK\0".as_ptr() as *const _);
+        }
+        mask
+    }
+
+    pub unsafe fn set_mask(&mut self, mask: u64) {
+        if libc::ptrace(linux::PTRACE_SETSIGMASK, self.pid, mem::size_of::<u64>(), &mask) < 0 {
+            libc::perror(b"PTRACE_SETSIGMASK\0".as_ptr() as *const _);
+        }
+    }
+
     pub fn args(&self) -> (u64, u64, u64, u64, u64, u64) {
         (
             self.regs.orig_rax,
@@ -97,10 +361,125 @@ impl Process {
         self.regs.r8 = value;
     }
 
+    pub fn set_g(&mut self, value: u64) {
+        self.regs.r9 = value;
+    }
+
+    /// Run a Linux system call in the tracee while it is stopped on entry to
+    /// another system call, which is then restarted. Returns the raw result
+    pub unsafe fn inject(&mut self, nr: usize, args: [u64; 6]) -> Result<u64, i32> {
+        let regs = self.regs;
+
+        // Set up the injected system call
+        self.regs.orig_rax = nr as u64;
+        self.regs.rdi = args[0];
+        self.regs.rsi = args[1];
+        self.regs.rdx = args[2];
+        self.regs.r10 = args[3];
+        self.regs.r8 = args[4];
+        self.regs.r9 = args[5];
+        self.set();
+
+        // Call the system call
+        self.step()?;
+        self.get();
+        let result = self.regs.rax;
+
+        // Move back to the syscall instruction, which is two bytes long, and
+        // enter the original system call again
+        self.regs = regs;
+        self.regs.rip -= 2;
+        self.regs.rax = regs.orig_rax;
+        self.set();
+        self.step()?;
+
+        self.regs = regs;
+        Ok(result)
+    }
+
+    /// Run a Linux system call in the tracee while it is stopped on exit from
+    /// another system call, which then returns as before. Returns the raw
+    /// result
+    pub unsafe fn inject_exit(&mut self, nr: usize, args: [u64; 6]) -> Result<u64, i32> {
+        let regs = self.regs;
+
+        // Move back to the syscall instruction, and set up the injected
+        // system call
+        self.regs.rip -= 2;
+        self.regs.rax = nr as u64;
+        self.regs.rdi = args[0];
+        self.regs.rsi = args[1];
+        self.regs.rdx = args[2];
+        self.regs.r10 = args[3];
+        self.regs.r8 = args[4];
+        self.regs.r9 = args[5];
+        self.set();
+
+        // Enter and call the system call
+        self.step()?;
+        self.step()?;
+        self.get();
+        let result = self.regs.rax;
+
+        // Return from the original system call
+        self.regs = regs;
+        self.set();
+        Ok(result)
+    }
+
+    /// Get the address of memory in the tracee of at least `size` bytes that
+    /// can be used to pass arguments to Linux. The memory is mapped on first
+    /// use, and grown when necessary
+    pub unsafe fn scratch(&mut self, size: usize) -> Result<syscall::Result<usize>, i32> {
+        if let Some((address, scratch_size)) = self.scratch {
+            if size <= scratch_size {
+                return Ok(Ok(address));
+            }
+
+            self.unmap_scratch()?;
+        }
+
+        let scratch_size = cmp::max((size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1), PAGE_SIZE);
+        let result = self.inject(nr::MMAP, [
+            0,
+            scratch_size as u64,
+            (libc::PROT_READ | libc::PROT_WRITE) as u64,
+            (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as u64,
+            !0,
+            0
+        ])?;
+
+        // The address space may be exhausted, which fails the system call
+        if (result as i64) < 0 && (result as i64) > -4096 {
+            return Ok(Err(syscall::Error::new(syscall::ENOMEM)));
+        }
+
+        let address = result as usize;
+        self.scratch = Some((address, scratch_size));
+        Ok(Ok(address))
+    }
+
+    /// Unmap the scratch memory, for example when the thread exits
+    pub unsafe fn unmap_scratch(&mut self) -> Result<(), i32> {
+        if let Some((address, size)) = self.scratch.take() {
+            self.inject(nr::MUNMAP, [address as u64, size as u64, 0, 0, 0, 0])?;
+        }
+        Ok(())
+    }
+
+    /// Address of the scratch memory, which must have been mapped by `scratch`
+    pub fn scratch_address(&self) -> usize {
+        self.scratch.map_or(0, |(address, _size)| address)
+    }
+
     pub fn result(&self) -> syscall::Result<usize> {
         syscall::Error::demux(self.regs.rax as usize)
     }
 
+    pub fn set_result(&mut self, result: syscall::Result<usize>) {
+        self.regs.rax = syscall::Error::mux(result) as u64;
+    }
+
     pub unsafe fn read_type<T: Clone + Default>(&mut self, address: *const T, length: usize) -> syscall::Result<Vec<T>> {
         let mut buffer = vec![T::default(); length];
 
@@ -156,4 +535,38 @@ impl Process {
     pub unsafe fn pwrite(&mut self, address: usize, buffer: &[u8]) -> syscall::Result<()> {
         self.write_type(address as *mut u8, buffer)
     }
+
+    /// Read a NUL-terminated string, without the NUL. The string is read up
+    /// to the end of each page, so that unmapped pages after it are not read
+    pub unsafe fn pread_cstr(&mut self, address: usize) -> syscall::Result<Vec<u8>> {
+        let mut string = Vec::new();
+        let mut address = address;
+        loop {
+            let mut buffer = [0; PAGE_SIZE];
+            let length = PAGE_SIZE - address % PAGE_SIZE;
+
+            let local_iov = libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: length,
+            };
+
+            let remote_iov = libc::iovec {
+                iov_base: address as *mut libc::c_void,
+                iov_len: length,
+            };
+
+            if libc::process_vm_readv(self.pid, &local_iov as *const _, 1, &remote_iov as *const _, 1, 0) <= 0 {
+                return Err(syscall::Error::new(syscall::EFAULT));
+            }
+
+            match buffer[..length].iter().position(|&b| b == 0) {
+                Some(end) => {
+                    string.extend_from_slice(&buffer[..end]);
+                    return Ok(string);
+                },
+                None => string.extend_from_slice(&buffer[..length]),
+            }
+            address += length;
+        }
+    }
 }
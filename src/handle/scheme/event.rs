@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use syscall::data::Event;
+use syscall::error::*;
+use syscall::flag::O_NONBLOCK;
+
+use super::Scheme;
+
+/// A file descriptor whose events are watched by a queue
+#[derive(Clone)]
+struct Registration {
+    /// Scheme and handle of the emulated resource
+    scheme: Vec<u8>,
+    id: usize,
+    flags: usize,
+    data: usize,
+}
+
+#[derive(Clone)]
+struct Queue {
+    nonblock: bool,
+    /// Registrations by file descriptor
+    registrations: BTreeMap<usize, Registration>,
+    /// Events that are ready, but were not read yet
+    ready: VecDeque<Event>,
+}
+
+/// The `event:` scheme, where each handle is a queue. Writing an `Event`
+/// registers interest in the events of a file descriptor, and reading
+/// returns the events that happened. The registrations are made and polled
+/// by `Schemes`, which knows the other schemes
+pub struct EventScheme {
+    next_id: usize,
+    queues: BTreeMap<usize, Queue>,
+}
+
+impl EventScheme {
+    pub fn new() -> EventScheme {
+        EventScheme {
+            next_id: 0,
+            queues: BTreeMap::new(),
+        }
+    }
+
+    fn insert(&mut self, queue: Queue) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queues.insert(id, queue);
+        id
+    }
+
+    fn queue(&self, id: usize) -> Result<&Queue> {
+        self.queues.get(&id).ok_or(Error::new(EBADF))
+    }
+
+    fn queue_mut(&mut self, id: usize) -> Result<&mut Queue> {
+        self.queues.get_mut(&id).ok_or(Error::new(EBADF))
+    }
+
+    /// Watch the events out of `flags` of the resource `scheme` and `file`,
+    /// which is the file descriptor `fd`. Events out of `ready` have
+    /// happened already. Flags of zero remove the registration
+    pub fn register(&mut self, id: usize, fd: usize, scheme: &[u8], file: usize, flags: usize, data: usize, ready: usize) -> Result<()> {
+        let queue = self.queue_mut(id)?;
+        if flags == 0 {
+            queue.registrations.remove(&fd);
+            return Ok(());
+        }
+
+        queue.registrations.insert(fd, Registration {
+            scheme: scheme.to_vec(),
+            id: file,
+            flags,
+            data,
+        });
+        if ready & flags != 0 {
+            queue.ready.push_back(Event {
+                id: fd,
+                flags: ready & flags,
+                data,
+            });
+        }
+        Ok(())
+    }
+
+    /// Take up to `count` events of a queue, using `poll` to find the
+    /// events of a resource. Returns EAGAIN if there are none and the queue
+    /// does not block
+    pub fn events<F: FnMut(&[u8], usize) -> usize>(&mut self, id: usize, count: usize, mut poll: F) -> Result<Vec<Event>> {
+        let queue = self.queue_mut(id)?;
+        for (fd, registration) in queue.registrations.iter() {
+            let flags = poll(&registration.scheme, registration.id) & registration.flags;
+            if flags != 0 {
+                queue.ready.push_back(Event {
+                    id: *fd,
+                    flags,
+                    data: registration.data,
+                });
+            }
+        }
+
+        if queue.ready.is_empty() && queue.nonblock {
+            return Err(Error::new(EAGAIN));
+        }
+
+        let count = count.min(queue.ready.len());
+        Ok(queue.ready.drain(..count).collect())
+    }
+
+    /// The shortest time until a resource of a queue may have an event,
+    /// using `timeout` to find the time of a resource
+    pub fn timeout<F: Fn(&[u8], usize) -> Option<Duration>>(&self, id: usize, timeout: F) -> Result<Option<Duration>> {
+        Ok(self.queue(id)?.registrations.values()
+            .filter_map(|registration| timeout(&registration.scheme, registration.id))
+            .min())
+    }
+}
+
+impl Scheme for EventScheme {
+    fn open(&mut self, _path: &[u8], flags: usize) -> Result<usize> {
+        Ok(self.insert(Queue {
+            nonblock: flags & O_NONBLOCK > 0,
+            registrations: BTreeMap::new(),
+            ready: VecDeque::new(),
+        }))
+    }
+
+    fn dup(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let queue = self.queue(id)?.clone();
+        Ok(self.insert(queue))
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        self.queue(id)?;
+        let path = b"event:";
+        let count = path.len().min(buf.len());
+        buf[..count].copy_from_slice(&path[..count]);
+        Ok(count)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.queues.remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{self as unix_fs, MetadataExt};
+use std::path::Path;
+use std::{cmp, fs, io};
+
+use syscall::data::Stat;
+use syscall::error::*;
+use syscall::flag::{O_NOFOLLOW, SEEK_CUR, SEEK_END, SEEK_SET};
+
+use super::super::errno::convert_errno;
+use super::{Access, Scheme};
+
+/// Prefix of whiteouts, which are files in the upper layer that hide a file
+/// with the rest of the name in the lower layers
+const WHITEOUT: &[u8] = b".wh.";
+
+fn exists(lpath: &[u8]) -> bool {
+    fs::symlink_metadata(Path::new(OsStr::from_bytes(lpath))).is_ok()
+}
+
+fn join(layer: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut lpath = layer.to_vec();
+    for part in parts {
+        lpath.push(b'/');
+        lpath.extend_from_slice(part);
+    }
+    if lpath.is_empty() {
+        lpath.push(b'/');
+    }
+    lpath
+}
+
+fn whiteout(upper: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let (name, parent) = parts.split_last().unwrap();
+    let mut lpath = join(upper, parent);
+    lpath.push(b'/');
+    lpath.extend_from_slice(WHITEOUT);
+    lpath.extend_from_slice(name);
+    lpath
+}
+
+/// Maximum number of symbolic links that are followed in one path, like
+/// MAXSYMLINKS of Linux
+const MAX_LINKS: usize = 40;
+
+fn convert_io(err: io::Error) -> Error {
+    let errno = err.raw_os_error()
+        .and_then(convert_errno)
+        .unwrap_or(EIO);
+    Error::new(errno)
+}
+
+/// A directory that is opened to list the names in all layers
+#[derive(Clone)]
+struct Dir {
+    /// Path of the directory in the scheme
+    path: Vec<u8>,
+    /// Linux path of the directory in the topmost layer that has it
+    lpath: Vec<u8>,
+    /// Names separated by newlines, like directories of redoxfs
+    data: Vec<u8>,
+    offset: usize,
+}
+
+/// The `file:` scheme, which is a union of Linux directories, for example
+/// a Redox sysroot with a directory that receives all changes. Files are
+/// handled by Linux, but directories are emulated to merge the layers
+pub struct FileScheme {
+    /// Canonical Linux paths of the read-only layers, without trailing
+    /// slash. The first layer hides the others
+    lowers: Vec<Vec<u8>>,
+    /// Canonical Linux path of the writable layer. Without one, changes are
+    /// made in the lower layers
+    upper: Option<Vec<u8>>,
+    next_id: usize,
+    dirs: BTreeMap<usize, Dir>,
+}
+
+impl FileScheme {
+    pub fn new(lowers: &[&[u8]], upper: Option<&[u8]>) -> FileScheme {
+        let trim = |layer: &[u8]| {
+            let mut layer = layer.to_vec();
+            while layer.ends_with(b"/") {
+                layer.pop();
+            }
+            layer
+        };
+
+        FileScheme {
+            lowers: lowers.iter().map(|layer| trim(layer)).collect(),
+            upper: upper.map(trim),
+            next_id: 0,
+            dirs: BTreeMap::new(),
+        }
+    }
+
+    /// Check if lower layers are hidden at a path, or at one of its parents
+    fn whited_out(&self, parts: &[&[u8]]) -> bool {
+        match self.upper {
+            Some(ref upper) => (1..=parts.len()).any(|i| exists(&whiteout(upper, &parts[..i]))),
+            None => false,
+        }
+    }
+
+    /// Split a path into its parts, resolving `..` and symbolic links inside
+    /// the union so that paths cannot escape the layers. The last part is
+    /// only resolved if `follow` is set
+    fn resolve(&self, path: &[u8], follow: bool) -> Result<Vec<Vec<u8>>> {
+        let mut parts: Vec<Vec<u8>> = Vec::new();
+        // Parts that are not resolved yet, in reverse order
+        let mut rest: Vec<Vec<u8>> = path.split(|b| b == &b'/').rev().map(|part| part.to_vec()).collect();
+        let mut links = 0;
+        while let Some(part) = rest.pop() {
+            match &part[..] {
+                b"" | b"." => continue,
+                b".." => {
+                    parts.pop();
+                    continue;
+                },
+                _ => parts.push(part),
+            }
+
+            if !follow && rest.iter().all(|part| part.is_empty() || part == b".") {
+                break;
+            }
+
+            let target = {
+                let refs: Vec<&[u8]> = parts.iter().map(|part| &part[..]).collect();
+                match self.lookup(&refs) {
+                    Some(lpath) => match fs::read_link(Path::new(OsStr::from_bytes(&lpath))) {
+                        Ok(target) => target,
+                        Err(_) => continue,
+                    },
+                    None => continue,
+                }
+            };
+
+            links += 1;
+            if links > MAX_LINKS {
+                return Err(Error::new(ELOOP));
+            }
+
+            // Absolute targets start at the root of the union, and relative
+            // targets at the directory of the link
+            let target = target.as_os_str().as_bytes();
+            parts.pop();
+            if target.starts_with(b"/") {
+                parts.clear();
+            }
+            rest.extend(target.split(|b| b == &b'/').rev().map(|part| part.to_vec()));
+        }
+        Ok(parts)
+    }
+
+    /// Find the layer that provides a path
+    fn lookup(&self, parts: &[&[u8]]) -> Option<Vec<u8>> {
+        if let Some(ref upper) = self.upper {
+            let lpath = join(upper, parts);
+            if exists(&lpath) {
+                return Some(lpath);
+            }
+
+            if self.whited_out(parts) {
+                return None;
+            }
+        }
+
+        self.lowers.iter()
+            .map(|lower| join(lower, parts))
+            .find(|lpath| exists(lpath))
+    }
+
+    /// Find the Linux path of a file that is read, or of a file that does
+    /// not exist in the topmost layer
+    fn lookup_or_top(&self, parts: &[&[u8]]) -> Vec<u8> {
+        self.lookup(parts).unwrap_or_else(|| {
+            let top = self.upper.as_ref().or(self.lowers.first());
+            join(top.map_or(&b""[..], |layer| &layer[..]), parts)
+        })
+    }
+
+    /// Copy a file and its parents from the lower layers into the upper
+    /// layer, if they are not there yet
+    fn copy_up(&self, upper: &[u8], parts: &[&[u8]]) -> io::Result<Vec<u8>> {
+        for i in 1..=parts.len() {
+            let ulpath = join(upper, &parts[..i]);
+            if exists(&ulpath) {
+                continue;
+            }
+
+            // Files that are not found are created by Linux, if requested
+            let lpath = match self.lookup(&parts[..i]) {
+                Some(lpath) => lpath,
+                None => break,
+            };
+
+            let upath = Path::new(OsStr::from_bytes(&ulpath));
+            let path = Path::new(OsStr::from_bytes(&lpath));
+            let metadata = fs::symlink_metadata(path)?;
+            if metadata.file_type().is_symlink() {
+                unix_fs::symlink(fs::read_link(path)?, upath)?;
+            } else if metadata.is_dir() {
+                fs::create_dir(upath)?;
+                fs::set_permissions(upath, metadata.permissions())?;
+            } else {
+                fs::copy(path, upath)?;
+            }
+        }
+
+        Ok(join(upper, parts))
+    }
+
+    /// Names in a directory of all layers, except for whiteouts and the
+    /// files that they hide
+    fn entries(&self, parts: &[&[u8]]) -> io::Result<BTreeSet<Vec<u8>>> {
+        let read_dir = |lpath: &[u8]| -> io::Result<Vec<Vec<u8>>> {
+            let mut names = Vec::new();
+            if exists(lpath) {
+                for entry_res in fs::read_dir(Path::new(OsStr::from_bytes(lpath)))? {
+                    names.push(entry_res?.file_name().as_bytes().to_vec());
+                }
+            }
+            Ok(names)
+        };
+
+        let mut hidden = BTreeSet::new();
+        let mut entries = BTreeSet::new();
+        if let Some(ref upper) = self.upper {
+            for name in read_dir(&join(upper, parts))? {
+                if name.starts_with(WHITEOUT) {
+                    hidden.insert(name[WHITEOUT.len()..].to_vec());
+                } else {
+                    entries.insert(name);
+                }
+            }
+        }
+
+        if !self.whited_out(parts) {
+            for lower in self.lowers.iter() {
+                for name in read_dir(&join(lower, parts))? {
+                    if !hidden.contains(&name) {
+                        entries.insert(name);
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn rename_layers(&self, upper: &[u8], old_parts: &[&[u8]], parts: &[&[u8]]) -> io::Result<Result<usize>> {
+        let lower = |parts: &[&[u8]]| {
+            !self.whited_out(parts) && self.lowers.iter().any(|lower| exists(&join(lower, parts)))
+        };
+        let lower_dir = |parts: &[&[u8]]| {
+            lower(parts) && self.lookup(parts).map_or(false, |lpath| {
+                fs::symlink_metadata(Path::new(OsStr::from_bytes(&lpath))).map(|metadata| metadata.is_dir()).unwrap_or(false)
+            })
+        };
+
+        if self.lookup(old_parts).is_none() {
+            return Ok(Err(Error::new(ENOENT)));
+        }
+
+        // Directories are copied up without their contents, so directories
+        // of the lower layers cannot be moved or replaced, like overlayfs
+        if lower_dir(old_parts) || lower_dir(parts) {
+            return Ok(Err(Error::new(EXDEV)));
+        }
+
+        let old_ulpath = self.copy_up(upper, old_parts)?;
+        let (_name, parent) = parts.split_last().unwrap();
+        self.copy_up(upper, parent)?;
+        let ulpath = join(upper, parts);
+        fs::rename(Path::new(OsStr::from_bytes(&old_ulpath)), Path::new(OsStr::from_bytes(&ulpath)))?;
+
+        // The new name is no longer hidden, and the old one is hidden in the
+        // lower layers
+        let new_whiteout = whiteout(upper, parts);
+        if exists(&new_whiteout) {
+            fs::remove_file(Path::new(OsStr::from_bytes(&new_whiteout)))?;
+        }
+        if lower(old_parts) {
+            fs::File::create(Path::new(OsStr::from_bytes(&whiteout(upper, old_parts))))?;
+        }
+
+        Ok(Ok(0))
+    }
+
+    fn dir(&mut self, id: usize) -> Result<&mut Dir> {
+        self.dirs.get_mut(&id).ok_or(Error::new(EBADF))
+    }
+
+    fn insert_dir(&mut self, dir: Dir) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.dirs.insert(id, dir);
+        id
+    }
+
+    fn remove_layers(&self, upper: &[u8], parts: &[&[u8]], dir: bool) -> io::Result<Result<usize>> {
+        let lpath = match self.lookup(parts) {
+            Some(lpath) => lpath,
+            None => return Ok(Err(Error::new(ENOENT))),
+        };
+
+        let metadata = fs::symlink_metadata(Path::new(OsStr::from_bytes(&lpath)))?;
+        if dir && !metadata.is_dir() {
+            return Ok(Err(Error::new(ENOTDIR)));
+        } else if !dir && metadata.is_dir() {
+            return Ok(Err(Error::new(EISDIR)));
+        } else if dir && !self.entries(parts)?.is_empty() {
+            return Ok(Err(Error::new(ENOTEMPTY)));
+        }
+
+        // The upper directory may still contain whiteouts
+        let ulpath = join(upper, parts);
+        if exists(&ulpath) {
+            let upath = Path::new(OsStr::from_bytes(&ulpath));
+            if dir {
+                fs::remove_dir_all(upath)?;
+            } else {
+                fs::remove_file(upath)?;
+            }
+        }
+
+        // Hide the file in the lower layers
+        let lower = !self.whited_out(parts) && self.lowers.iter().any(|lower| exists(&join(lower, parts)));
+        if lower {
+            let (_name, parent) = parts.split_last().unwrap();
+            self.copy_up(upper, parent)?;
+            fs::File::create(Path::new(OsStr::from_bytes(&whiteout(upper, parts))))?;
+        }
+
+        Ok(Ok(0))
+    }
+}
+
+impl Scheme for FileScheme {
+    fn path(&self, path: &[u8], access: Access, follow: bool) -> Option<Result<Vec<u8>>> {
+        let parts = match self.resolve(path, follow) {
+            Ok(parts) => parts,
+            Err(err) => return Some(Err(err)),
+        };
+        let parts: Vec<&[u8]> = parts.iter().map(|part| &part[..]).collect();
+
+        // Whiteouts cannot be accessed directly
+        if parts.iter().any(|part| part.starts_with(WHITEOUT)) {
+            return Some(Err(Error::new(EACCES)));
+        }
+
+        Some(match (access, self.upper.as_ref()) {
+            (Access::Write, Some(upper)) => self.copy_up(upper, &parts).map_err(convert_io),
+            _ => Ok(self.lookup_or_top(&parts)),
+        })
+    }
+
+    fn lpath(&self, lpath: &[u8]) -> Option<Vec<u8>> {
+        self.upper.iter().chain(self.lowers.iter()).filter_map(|layer| {
+            if lpath == &layer[..] {
+                Some(b"/".to_vec())
+            } else if lpath.starts_with(layer) && lpath[layer.len()..].starts_with(b"/") {
+                Some(lpath[layer.len()..].to_vec())
+            } else {
+                None
+            }
+        }).next()
+    }
+
+    fn remove(&self, path: &[u8], dir: bool) -> Option<Result<usize>> {
+        // Without an upper layer, Linux removes the file
+        let upper = self.upper.as_ref()?;
+
+        // The root cannot be removed, and whiteouts cannot be accessed
+        let parts = match self.resolve(path, false) {
+            Ok(parts) => parts,
+            Err(err) => return Some(Err(err)),
+        };
+        let parts: Vec<&[u8]> = parts.iter().map(|part| &part[..]).collect();
+        if parts.is_empty() {
+            return Some(Err(Error::new(EBUSY)));
+        } else if parts.iter().any(|part| part.starts_with(WHITEOUT)) {
+            return Some(Err(Error::new(EACCES)));
+        }
+
+        Some(self.remove_layers(upper, &parts, dir).unwrap_or_else(|err| Err(convert_io(err))))
+    }
+
+    fn rename(&self, old_path: &[u8], path: &[u8]) -> Option<Result<usize>> {
+        // Without an upper layer, Linux renames the file
+        let upper = self.upper.as_ref()?;
+
+        let (old_parts, parts) = match self.resolve(old_path, false).and_then(|old_parts| {
+            self.resolve(path, false).map(|parts| (old_parts, parts))
+        }) {
+            Ok(parts) => parts,
+            Err(err) => return Some(Err(err)),
+        };
+        let old_parts: Vec<&[u8]> = old_parts.iter().map(|part| &part[..]).collect();
+        let parts: Vec<&[u8]> = parts.iter().map(|part| &part[..]).collect();
+
+        // The root cannot be renamed, and whiteouts cannot be accessed
+        if old_parts.is_empty() || parts.is_empty() {
+            return Some(Err(Error::new(EBUSY)));
+        } else if old_parts.iter().chain(parts.iter()).any(|part| part.starts_with(WHITEOUT)) {
+            return Some(Err(Error::new(EACCES)));
+        }
+
+        Some(self.rename_layers(upper, &old_parts, &parts).unwrap_or_else(|err| Err(convert_io(err))))
+    }
+
+    /// Open a directory, other files are opened by Linux
+    fn open(&mut self, path: &[u8], flags: usize) -> Result<usize> {
+        let parts = self.resolve(path, flags & O_NOFOLLOW == 0)?;
+        let parts: Vec<&[u8]> = parts.iter().map(|part| &part[..]).collect();
+        let lpath = self.lookup(&parts).ok_or(Error::new(ENOENT))?;
+        if !fs::metadata(Path::new(OsStr::from_bytes(&lpath))).map_err(convert_io)?.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        let mut data = Vec::new();
+        for name in self.entries(&parts).map_err(convert_io)? {
+            if !data.is_empty() {
+                data.push(b'\n');
+            }
+            data.extend_from_slice(&name);
+        }
+
+        let dir = Dir {
+            path: join(b"", &parts),
+            lpath,
+            data,
+            offset: 0,
+        };
+        Ok(self.insert_dir(dir))
+    }
+
+    fn dup(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let dir = self.dir(id)?.clone();
+        Ok(self.insert_dir(dir))
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let dir = self.dir(id)?;
+        let count = cmp::min(buf.len(), dir.data.len().saturating_sub(dir.offset));
+        buf[..count].copy_from_slice(&dir.data[dir.offset..dir.offset + count]);
+        dir.offset += count;
+        Ok(count)
+    }
+
+    fn seek(&mut self, id: usize, pos: usize, whence: usize) -> Result<usize> {
+        let dir = self.dir(id)?;
+        let offset = match whence {
+            SEEK_SET => pos as isize,
+            SEEK_CUR => dir.offset as isize + pos as isize,
+            SEEK_END => dir.data.len() as isize + pos as isize,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        if offset < 0 {
+            return Err(Error::new(EINVAL));
+        }
+        dir.offset = offset as usize;
+        Ok(dir.offset)
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut path = b"file:".to_vec();
+        path.extend_from_slice(&self.dir(id)?.path);
+        let count = path.len().min(buf.len());
+        buf[..count].copy_from_slice(&path[..count]);
+        Ok(count)
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let dir = self.dir(id)?;
+        let metadata = fs::metadata(Path::new(OsStr::from_bytes(&dir.lpath))).map_err(convert_io)?;
+
+        *stat = Stat {
+            st_dev: metadata.dev(),
+            st_ino: metadata.ino(),
+            st_mode: metadata.mode() as _,
+            st_nlink: metadata.nlink() as _,
+            st_uid: metadata.uid(),
+            st_gid: metadata.gid(),
+            st_size: dir.data.len() as _,
+            st_blksize: metadata.blksize() as _,
+            st_blocks: metadata.blocks(),
+            st_mtime: metadata.mtime() as _,
+            st_mtime_nsec: metadata.mtime_nsec() as _,
+            st_atime: metadata.atime() as _,
+            st_atime_nsec: metadata.atime_nsec() as _,
+            st_ctime: metadata.ctime() as _,
+            st_ctime_nsec: metadata.ctime_nsec() as _,
+        };
+        Ok(0)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.dirs.remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(0)
+    }
+}
@@ -0,0 +1,232 @@
+//! Redox schemes, which are either mapped onto Linux paths or emulated by rine
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use syscall::data::{Event, Map, Stat, StatVfs, TimeSpec};
+use syscall::error::*;
+
+pub use self::device::DeviceScheme;
+pub use self::event::EventScheme;
+pub use self::file::FileScheme;
+pub use self::time::TimeScheme;
+
+mod device;
+mod event;
+mod file;
+mod time;
+
+/// Longest time that a read of an event queue waits before polling again,
+/// as events may be registered meanwhile
+const EVENT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How a path is used, which decides the layer of a union of directories
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// The file is looked up or read
+    Read,
+    /// The file is created or changed
+    Write,
+}
+
+/// A Redox scheme. Schemes that map onto Linux files only implement `path`,
+/// and Linux handles their file descriptors. Emulated schemes implement the
+/// other methods, and their file descriptors are virtual
+pub trait Scheme {
+    /// Find the Linux path of a path in this scheme, or `None` if the
+    /// scheme is emulated. A symbolic link at the end of the path is only
+    /// followed if `follow` is set
+    fn path(&self, _path: &[u8], _access: Access, _follow: bool) -> Option<Result<Vec<u8>>> {
+        None
+    }
+
+    /// Remove a file or directory if the scheme does this itself. Returns
+    /// `None` if Linux should remove the file at its Linux path
+    fn remove(&self, _path: &[u8], _dir: bool) -> Option<Result<usize>> {
+        None
+    }
+
+    /// Rename a file or directory inside this scheme if the scheme does
+    /// this itself. Returns `None` if Linux should rename the file at its
+    /// Linux path
+    fn rename(&self, _old_path: &[u8], _path: &[u8]) -> Option<Result<usize>> {
+        None
+    }
+
+    /// Find the path in this scheme of a Linux path, the reverse of `path`
+    fn lpath(&self, _lpath: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn open(&mut self, _path: &[u8], _flags: usize) -> Result<usize> {
+        Err(Error::new(ENOENT))
+    }
+
+    fn dup(&mut self, _id: usize, _buf: &[u8]) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn read(&mut self, _id: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn write(&mut self, _id: usize, _buf: &[u8]) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn seek(&mut self, _id: usize, _pos: usize, _whence: usize) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn fchmod(&mut self, _id: usize, _mode: u16) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn fchown(&mut self, _id: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    /// Register interest in the events out of `flags`, returning the ones
+    /// that are ready already. Later events are found with `poll`
+    fn fevent(&mut self, _id: usize, _flags: usize) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    /// Events registered with `fevent` that happened since the last poll,
+    /// like EVENT_READ when a timer has expired
+    fn poll(&mut self, _id: usize) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    /// Time until `poll` may return an event, or `None` if it is unknown
+    fn timeout(&self, _id: usize) -> Option<Duration> {
+        None
+    }
+
+    fn fmap(&mut self, _id: usize, _map: &Map) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn fpath(&mut self, _id: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    /// Rename the file to a path in the same scheme
+    fn frename(&mut self, _id: usize, _path: &[u8]) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn fstat(&mut self, _id: usize, _stat: &mut Stat) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn fstatvfs(&mut self, _id: usize, _stat: &mut StatVfs) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn fsync(&mut self, _id: usize) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn ftruncate(&mut self, _id: usize, _len: usize) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn futimens(&mut self, _id: usize, _times: &[TimeSpec]) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+
+    fn close(&mut self, _id: usize) -> Result<usize> {
+        Err(Error::new(EBADF))
+    }
+}
+
+/// Registry of schemes, indexed by name. The `event:` scheme is kept apart,
+/// as it polls the other schemes
+pub struct Schemes {
+    schemes: BTreeMap<Vec<u8>, Box<dyn Scheme>>,
+    event: EventScheme,
+}
+
+impl Schemes {
+    /// Create the default schemes, with the given `file:` scheme
+    pub fn new(file: FileScheme) -> Schemes {
+        let mut schemes = Schemes {
+            schemes: BTreeMap::new(),
+            event: EventScheme::new(),
+        };
+
+        schemes.insert(b"file", Box::new(file));
+        schemes.insert(b"null", Box::new(DeviceScheme::new(b"/dev/null")));
+        schemes.insert(b"rand", Box::new(DeviceScheme::new(b"/dev/urandom")));
+        schemes.insert(b"time", Box::new(TimeScheme::new()));
+        schemes.insert(b"zero", Box::new(DeviceScheme::new(b"/dev/zero")));
+
+        schemes
+    }
+
+    pub fn insert(&mut self, name: &[u8], scheme: Box<dyn Scheme>) {
+        self.schemes.insert(name.to_vec(), scheme);
+    }
+
+    pub fn get(&self, name: &[u8]) -> Option<&dyn Scheme> {
+        if name == b"event" {
+            return Some(&self.event);
+        }
+        self.schemes.get(name).map(|scheme| scheme.as_ref())
+    }
+
+    /// Find the Redox path of a Linux path. If several schemes contain it,
+    /// the most specific one is used, such as `null:` for /dev/null
+    pub fn rpath(&self, lpath: &[u8]) -> Option<Vec<u8>> {
+        let (name, path) = self.schemes.iter()
+            .filter_map(|(name, scheme)| scheme.lpath(lpath).map(|path| (name, path)))
+            .min_by_key(|&(_name, ref path)| path.len())?;
+
+        let mut rpath = name.clone();
+        rpath.push(b':');
+        rpath.extend_from_slice(&path);
+        Some(rpath)
+    }
+
+    /// Call a scheme by name, returning ENODEV if it does not exist
+    pub fn call<T, F: FnOnce(&mut dyn Scheme) -> Result<T>>(&mut self, name: &[u8], f: F) -> Result<T> {
+        if name == b"event" {
+            return f(&mut self.event);
+        }
+        match self.schemes.get_mut(name) {
+            Some(scheme) => f(scheme.as_mut()),
+            None => Err(Error::new(ENODEV)),
+        }
+    }
+
+    /// Register the events out of `flags` of the resource `scheme` and `id`,
+    /// which is the file descriptor `fd`, with the event queue `queue`
+    pub fn fevent(&mut self, queue: usize, fd: usize, scheme: &[u8], id: usize, flags: usize, data: usize) -> Result<usize> {
+        let ready = if flags != 0 {
+            self.call(scheme, |scheme| scheme.fevent(id, flags))?
+        } else {
+            0
+        };
+        self.event.register(queue, fd, scheme, id, flags, data, ready)?;
+        Ok(0)
+    }
+
+    /// Take up to `count` events that are ready in the event queue `queue`
+    pub fn events(&mut self, queue: usize, count: usize) -> Result<Vec<Event>> {
+        let schemes = &mut self.schemes;
+        self.event.events(queue, count, |name, id| {
+            // Resources that were closed have no events
+            schemes.get_mut(name).and_then(|scheme| scheme.poll(id).ok()).unwrap_or(0)
+        })
+    }
+
+    /// Time to wait for the next event of the event queue `queue`
+    pub fn event_timeout(&self, queue: usize) -> Result<Duration> {
+        let schemes = &self.schemes;
+        let timeout_opt = self.event.timeout(queue, |name, id| {
+            schemes.get(name).and_then(|scheme| scheme.timeout(id))
+        })?;
+        Ok(timeout_opt.map_or(EVENT_INTERVAL, |timeout| timeout.min(EVENT_INTERVAL)))
+    }
+}
@@ -0,0 +1,187 @@
+use libc;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{io, mem, ptr, str};
+
+use syscall::data::TimeSpec;
+use syscall::error::*;
+use syscall::flag::EVENT_READ;
+
+use super::super::errno::convert_errno;
+use super::super::{convert_clock, convert_rtimespec};
+use super::Scheme;
+
+fn last_error() -> Error {
+    let errno = io::Error::last_os_error().raw_os_error()
+        .and_then(convert_errno)
+        .unwrap_or(EIO);
+    Error::new(errno)
+}
+
+struct Timer {
+    /// Redox clock, like CLOCK_MONOTONIC
+    clock: usize,
+    /// Linux timerfd of rine that expires when the timer does
+    lfd: libc::c_int,
+    /// Events registered with fevent
+    flags: usize,
+}
+
+/// The `time:` scheme, where `time:1` is the realtime clock and `time:4` the
+/// monotonic clock. Reading returns the current time, and writing a time
+/// arms a timer that sends EVENT_READ when it expires
+pub struct TimeScheme {
+    next_id: usize,
+    timers: BTreeMap<usize, Timer>,
+}
+
+impl TimeScheme {
+    pub fn new() -> TimeScheme {
+        TimeScheme {
+            next_id: 0,
+            timers: BTreeMap::new(),
+        }
+    }
+
+    fn create(&mut self, clock: usize) -> Result<usize> {
+        let lclock = convert_clock(clock as u64).ok_or(Error::new(ENOENT))?;
+        let lfd = unsafe { libc::timerfd_create(lclock, libc::TFD_NONBLOCK | libc::TFD_CLOEXEC) };
+        if lfd < 0 {
+            return Err(last_error());
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.timers.insert(id, Timer { clock, lfd, flags: 0 });
+        Ok(id)
+    }
+
+    fn timer(&self, id: usize) -> Result<&Timer> {
+        self.timers.get(&id).ok_or(Error::new(EBADF))
+    }
+
+    fn timer_mut(&mut self, id: usize) -> Result<&mut Timer> {
+        self.timers.get_mut(&id).ok_or(Error::new(EBADF))
+    }
+}
+
+impl Scheme for TimeScheme {
+    fn open(&mut self, path: &[u8], _flags: usize) -> Result<usize> {
+        let clock = str::from_utf8(path).ok()
+            .and_then(|path| path.parse::<usize>().ok())
+            .ok_or(Error::new(ENOENT))?;
+        self.create(clock)
+    }
+
+    fn dup(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        if !buf.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        // The new handle has the same clock, without the timer
+        let clock = self.timer(id)?.clock;
+        self.create(clock)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let clock = self.timer(id)?.clock;
+        let lclock = convert_clock(clock as u64).ok_or(Error::new(EBADF))?;
+
+        let mut ltime: libc::timespec = unsafe { mem::zeroed() };
+        if unsafe { libc::clock_gettime(lclock, &mut ltime) } < 0 {
+            return Err(last_error());
+        }
+
+        let time = TimeSpec {
+            tv_sec: ltime.tv_sec as _,
+            tv_nsec: ltime.tv_nsec as _,
+        };
+        if buf.len() < time.len() {
+            return Err(Error::new(EINVAL));
+        }
+        buf[..time.len()].copy_from_slice(&time);
+        Ok(time.len())
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let lfd = self.timer(id)?.lfd;
+
+        let mut time = TimeSpec::default();
+        if buf.len() < time.len() {
+            return Err(Error::new(EINVAL));
+        }
+        let count = time.len();
+        time[..].copy_from_slice(&buf[..count]);
+
+        // A zero time would disarm the timer, but it has already expired
+        let mut value = convert_rtimespec(&time);
+        if value.tv_sec == 0 && value.tv_nsec == 0 {
+            value.tv_nsec = 1;
+        }
+
+        let ltimer = libc::itimerspec {
+            it_interval: libc::timespec { tv_sec: 0, tv_nsec: 0 },
+            it_value: value,
+        };
+        if unsafe { libc::timerfd_settime(lfd, libc::TFD_TIMER_ABSTIME, &ltimer, ptr::null_mut()) } < 0 {
+            return Err(last_error());
+        }
+        Ok(count)
+    }
+
+    fn fevent(&mut self, id: usize, flags: usize) -> Result<usize> {
+        // Expirations are left in the timerfd for poll
+        self.timer_mut(id)?.flags = flags;
+        Ok(0)
+    }
+
+    fn poll(&mut self, id: usize) -> Result<usize> {
+        let timer = self.timer(id)?;
+        if timer.flags & EVENT_READ == 0 {
+            return Ok(0);
+        }
+
+        // Reading the timerfd succeeds once the timer has expired
+        let mut expirations = 0u64;
+        let result = unsafe {
+            libc::read(timer.lfd, &mut expirations as *mut u64 as *mut libc::c_void, mem::size_of::<u64>())
+        };
+        if result > 0 {
+            Ok(EVENT_READ)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn timeout(&self, id: usize) -> Option<Duration> {
+        let timer = self.timer(id).ok()?;
+        if timer.flags & EVENT_READ == 0 {
+            return None;
+        }
+
+        // A disarmed timer has no expiration
+        let mut ltimer: libc::itimerspec = unsafe { mem::zeroed() };
+        if unsafe { libc::timerfd_gettime(timer.lfd, &mut ltimer) } < 0 {
+            return None;
+        }
+        let value = ltimer.it_value;
+        if value.tv_sec == 0 && value.tv_nsec == 0 {
+            None
+        } else {
+            Some(Duration::new(value.tv_sec as u64, value.tv_nsec as u32))
+        }
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let path = format!("time:{}", self.timer(id)?.clock);
+        let count = path.len().min(buf.len());
+        buf[..count].copy_from_slice(&path.as_bytes()[..count]);
+        Ok(count)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        let timer = self.timers.remove(&id).ok_or(Error::new(EBADF))?;
+        unsafe { libc::close(timer.lfd) };
+        Ok(0)
+    }
+}
@@ -0,0 +1,32 @@
+//! Conversion between Redox and Linux signal numbers
+
+use libc;
+use syscall::flag;
+
+macro_rules! signals {
+    ($convert:ident, $from:ident, $to:ident) => (
+        signals!($convert, $from, $to,
+            SIGHUP, SIGINT, SIGQUIT, SIGILL, SIGTRAP, SIGABRT, SIGBUS, SIGFPE,
+            SIGKILL, SIGUSR1, SIGSEGV, SIGUSR2, SIGPIPE, SIGALRM, SIGTERM,
+            SIGSTKFLT, SIGCHLD, SIGCONT, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU,
+            SIGURG, SIGXCPU, SIGXFSZ, SIGVTALRM, SIGPROF, SIGWINCH, SIGIO,
+            SIGPWR, SIGSYS
+        )
+    );
+    ($convert:ident, $from:ident, $to:ident, $($name:ident),*) => (match $convert {
+        $($from::$name => Some($to::$name),)*
+        _ => None,
+    });
+}
+
+/// Convert a Redox signal into the Linux signal with the same meaning,
+/// returning `None` if Linux has no equivalent
+pub fn convert_signal(sig: usize) -> Option<libc::c_int> {
+    signals!(sig, flag, libc)
+}
+
+/// Convert a Linux signal into the Redox signal with the same meaning,
+/// returning `None` if Redox has no equivalent, such as for real-time signals
+pub fn convert_lsignal(lsig: libc::c_int) -> Option<usize> {
+    signals!(lsig, libc, flag)
+}
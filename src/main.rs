@@ -5,17 +5,29 @@ extern crate log;
 extern crate sc;
 extern crate syscall;
 
-use std::{env, ffi, process, ptr};
+use std::collections::{BTreeMap, BTreeSet};
+use std::{env, ffi, fs, process, ptr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
 
-use libc::{PTRACE_O_EXITKILL, PTRACE_O_TRACESYSGOOD, PTRACE_SETOPTIONS, PTRACE_TRACEME};
+use libc::{PTRACE_O_EXITKILL, PTRACE_O_TRACECLONE, PTRACE_O_TRACEEXEC, PTRACE_O_TRACEFORK, PTRACE_O_TRACESYSGOOD, PTRACE_O_TRACEVFORK};
+use libc::{PTRACE_EVENT_CLONE, PTRACE_EVENT_EXEC, PTRACE_EVENT_FORK, PTRACE_EVENT_VFORK, PTRACE_CONT, PTRACE_GETEVENTMSG, PTRACE_SEIZE, PTRACE_SYSCALL};
 use libc::{c_char, pid_t};
 use libc::{execv, fork, ptrace};
 
-use self::handle::handle;
+use self::handle::{handle, linux, signal, Access, FileScheme, Process, Scheme, Schemes};
 mod handle;
 
-unsafe fn child(path: *const c_char, argv: *const *const c_char) -> ! {
-    ptrace(PTRACE_TRACEME, 0, 0, 0);
+unsafe fn child(path: *const c_char, argv: *const *const c_char, cwd_opt: Option<*const c_char>) -> ! {
+    if let Some(cwd) = cwd_opt {
+        if libc::chdir(cwd) < 0 {
+            libc::perror(b"chdir\0".as_ptr() as *const _);
+            process::exit(1);
+        }
+    }
+
+    // Wait to be seized by the parent
+    libc::raise(libc::SIGSTOP);
 
     if execv(path, argv) < 0 {
         libc::perror(b"execv\0".as_ptr() as *const _);
@@ -24,26 +36,56 @@ unsafe fn child(path: *const c_char, argv: *const *const c_char) -> ! {
     process::exit(1);
 }
 
-unsafe fn parent(pid: pid_t) {
+unsafe fn parent(pid: pid_t, schemes: Schemes) {
     env_logger::init();
 
+    let mut status = 0;
+    if libc::waitpid(pid, &mut status, libc::WUNTRACED) < 0 {
+        libc::perror(b"waitpid\0".as_ptr() as *const _);
+        process::exit(1);
+    }
+
+    // Seize the stopped child, so that Linux reports group-stops as
+    // PTRACE_EVENT_STOP and they can be kept with PTRACE_LISTEN
+    if ptrace(
+        PTRACE_SEIZE,
+        pid,
+        0,
+        PTRACE_O_EXITKILL | PTRACE_O_TRACESYSGOOD |
+        PTRACE_O_TRACECLONE | PTRACE_O_TRACEEXEC | PTRACE_O_TRACEFORK | PTRACE_O_TRACEVFORK
+    ) < 0 {
+        libc::perror(b"PTRACE_SEIZE\0".as_ptr() as *const _);
+        process::exit(1);
+    }
+    libc::kill(pid, libc::SIGCONT);
+
+    // The child is still rine until it executes the program. Linux reports
+    // the exec before the exit of execve, which is skipped as well
+    let mut exec = false;
     loop {
-        let mut status = 0;
         if libc::waitpid(pid, &mut status, 0) < 0 {
             libc::perror(b"waitpid\0".as_ptr() as *const _);
             process::exit(1);
         }
 
         trace!("waitpid {:#x}", status);
-        if libc::WIFSTOPPED(status) && libc::WSTOPSIG(status) == (0x80 | libc::SIGTRAP) {
+        if libc::WIFSTOPPED(status) && status >> 16 == PTRACE_EVENT_EXEC {
+            trace!("  EXEC");
+            exec = true;
+            ptrace(PTRACE_SYSCALL, pid, 0, 0);
+        } else if exec && libc::WIFSTOPPED(status) && libc::WSTOPSIG(status) == libc::SIGTRAP | 0x80 {
             trace!("  SYSCALL");
+            break;
         } else if libc::WIFSTOPPED(status) {
             let signal = libc::WSTOPSIG(status);
             trace!("  STOPPED {}", signal);
-            break;
+            // Signals like SIGCONT are delivered, other stops are continued
+            let deliver = if status >> 16 == 0 { signal } else { 0 };
+            ptrace(if exec { PTRACE_SYSCALL } else { PTRACE_CONT }, pid, 0, deliver);
         } else if libc::WIFSIGNALED(status) {
             let signal = libc::WTERMSIG(status);
             trace!("  SIGNALED {}", signal);
+            process::exit(128 + signal);
         } else if libc::WIFEXITED(status) {
             let exit_status = libc::WEXITSTATUS(status);
             trace!("  EXIT {}", exit_status);
@@ -51,29 +93,183 @@ unsafe fn parent(pid: pid_t) {
         }
     }
 
-    ptrace(PTRACE_SETOPTIONS, pid, 0, PTRACE_O_EXITKILL | PTRACE_O_TRACESYSGOOD);
+    // Traced threads, indexed by thread ID
+    let mut processes = BTreeMap::new();
+    let mut p = Process::new(pid, schemes);
+    if let Err(status) = p.resume(0) {
+        process::exit(status);
+    }
+    processes.insert(pid, p);
+
+    // Threads that stopped before their parent reported the clone
+    let mut orphans = BTreeSet::new();
 
     loop {
-        if let Err(status) = handle(pid) {
-            println!("Process exited with status {}", status);
-            process::exit(status);
+        let mut status = 0;
+        let tid = libc::waitpid(-1, &mut status, libc::__WALL);
+        if tid < 0 {
+            libc::perror(b"waitpid\0".as_ptr() as *const _);
+            process::exit(1);
+        }
+
+        trace!("waitpid {} {:#x}", tid, status);
+        let exit_status = if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            trace!("  SIGNALED {}", signal);
+            Some(128 + signal)
+        } else if libc::WIFEXITED(status) {
+            let exit_status = libc::WEXITSTATUS(status);
+            trace!("  EXIT {}", exit_status);
+            Some(exit_status)
+        } else {
+            None
+        };
+
+        let mut result = Ok(());
+        if exit_status.is_none() {
+            let mut child_opt = None;
+            // Linux signal to deliver when resuming
+            let mut deliver = 0;
+            // Whether the thread is in a group-stop
+            let mut listen = false;
+            match processes.get_mut(&tid) {
+                Some(p) => {
+                    let event = status >> 16;
+                    if libc::WIFSTOPPED(status) && libc::WSTOPSIG(status) == (0x80 | libc::SIGTRAP) {
+                        trace!("  SYSCALL");
+                        result = handle(p);
+                    } else if event == PTRACE_EVENT_CLONE || event == PTRACE_EVENT_FORK || event == PTRACE_EVENT_VFORK {
+                        let mut child: libc::c_ulong = 0;
+                        ptrace(PTRACE_GETEVENTMSG, tid, 0, &mut child);
+                        trace!("  CLONE {}", child);
+                        child_opt = Some(p.spawn(child as pid_t));
+                    } else if event == PTRACE_EVENT_EXEC {
+                        trace!("  EXEC");
+                        p.exec();
+                    } else if event == linux::PTRACE_EVENT_STOP {
+                        let lsignal = libc::WSTOPSIG(status);
+                        trace!("  EVENT_STOP {}", lsignal);
+                        // A group-stop keeps the thread stopped, until Linux
+                        // wakes it up with SIGCONT. Other stops are the start
+                        // of new threads, or the end of a group-stop
+                        listen = match lsignal {
+                            libc::SIGSTOP | libc::SIGTSTP | libc::SIGTTIN | libc::SIGTTOU => true,
+                            _ => false,
+                        };
+                    } else if libc::WIFSTOPPED(status) {
+                        let lsignal = libc::WSTOPSIG(status);
+                        trace!("  STOPPED {}", lsignal);
+                        if p.signal_delivery() {
+                            result = signal(p, lsignal).map(|lsignal| deliver = lsignal);
+                        }
+                    }
+
+                    if result.is_ok() {
+                        result = if listen {
+                            p.listen()
+                        } else {
+                            p.resume(deliver)
+                        };
+                    }
+                },
+                None => {
+                    // Wait to resume this thread until its parent reports it
+                    orphans.insert(tid);
+                }
+            }
+
+            if let Some(mut child) = child_opt {
+                if orphans.remove(&child.pid) {
+                    if let Err(status) = child.resume(0) {
+                        process::exit(status);
+                    }
+                }
+                processes.insert(child.pid, child);
+            }
+        }
+
+        if let Some(status) = exit_status.or(result.err()) {
+            if let Some(mut p) = processes.remove(&tid) {
+                p.exit();
+            }
+            if tid == pid {
+                println!("Process exited with status {}", status);
+                process::exit(status);
+            }
         }
     }
 }
 
 fn main() {
+    let mut roots = Vec::new();
+    let mut upper_opt = None;
     let mut args = Vec::new();
-    for arg in env::args().skip(1) {
-        args.push(ffi::CString::new(arg).unwrap());
+    let mut env_args = env::args().skip(1);
+    while let Some(arg) = env_args.next() {
+        if args.is_empty() && (arg == "--root" || arg == "--upper") {
+            match env_args.next() {
+                Some(dir) => if arg == "--root" {
+                    roots.push(PathBuf::from(dir));
+                } else {
+                    upper_opt = Some(PathBuf::from(dir));
+                },
+                None => break,
+            }
+        } else {
+            args.push(arg);
+        }
     }
 
     if args.is_empty() {
-        eprintln!("rine [command]");
+        eprintln!("rine [--root directory]... [--upper directory] [command]");
         process::exit(1);
     }
 
+    if roots.is_empty() {
+        roots.push(PathBuf::from("/"));
+    }
+
+    // The roots are the layers of the file: scheme, the first one hiding the
+    // others. Changes are made in the upper directory, if there is one
+    let canonicalize = |dir: &PathBuf| match fs::canonicalize(dir) {
+        Ok(dir) => dir,
+        Err(err) => {
+            eprintln!("rine: {}: {}", dir.display(), err);
+            process::exit(1);
+        }
+    };
+    let roots: Vec<PathBuf> = roots.iter().map(&canonicalize).collect();
+    let upper_opt = upper_opt.as_ref().map(canonicalize);
+
+    let lowers: Vec<&[u8]> = roots.iter().map(|root| root.as_os_str().as_bytes()).collect();
+    let file = FileScheme::new(&lowers, upper_opt.as_ref().map(|upper| upper.as_os_str().as_bytes()));
+
+    // Absolute commands are found in the layers
+    let path = if args[0].starts_with('/') {
+        match file.path(args[0].as_bytes(), Access::Read, true) {
+            Some(Ok(lpath)) => lpath,
+            _ => args[0].as_bytes().to_vec(),
+        }
+    } else {
+        args[0].as_bytes().to_vec()
+    };
+    let path = ffi::CString::new(path).unwrap();
+
+    // Start in the first root if the working directory is outside of the layers
+    let inside = |dir: &PathBuf| roots.iter().chain(upper_opt.iter()).any(|root| dir.starts_with(root));
+    let cwd_opt = if env::current_dir().ok().map_or(false, |dir| inside(&dir)) {
+        None
+    } else {
+        Some(ffi::CString::new(roots[0].as_os_str().as_bytes()).unwrap())
+    };
+
+    let mut cargs = Vec::new();
+    for arg in args {
+        cargs.push(ffi::CString::new(arg).unwrap());
+    }
+
     let mut arg_ptrs = Vec::new();
-    for arg in args.iter() {
+    for arg in cargs.iter() {
         arg_ptrs.push(arg.as_ptr())
     }
     arg_ptrs.push(ptr::null());
@@ -81,11 +277,11 @@ fn main() {
     unsafe {
         let pid = fork();
         if pid == 0 {
-            child(arg_ptrs[0], arg_ptrs.as_ptr());
+            child(path.as_ptr(), arg_ptrs.as_ptr(), cwd_opt.as_ref().map(|cwd| cwd.as_ptr()));
         } else if pid < 0 {
             panic!("failed to clone");
         } else {
-            parent(pid);
+            parent(pid, Schemes::new(file));
         }
     }
 }